@@ -0,0 +1,366 @@
+use crate::cache::{PayloadCache, Selection};
+use crate::client::UpstreamClient;
+use crate::utils::{FetchError, Fetcher, InFlight};
+use crate::warming::Warmer;
+use futures::stream::{self, StreamExt};
+use rocket::http::{ContentType, Status};
+use rocket::request::{self, FromRequest, Request};
+use rocket::State;
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+/// The service refreshes made through the admin API are cached for, which
+/// only decides whether a failed refresh replaces a stale payload.
+const ADMIN_SERVICE: &str = "admin";
+
+/// How many payloads the admin API refreshes at once.
+const REFRESH_CONCURRENCY: usize = 8;
+
+/// Configuration for the admin API, read from the `admin` table of the
+/// configuration, e.g.
+///
+/// ```toml
+/// [default.admin]
+/// token = "..."
+/// ```
+///
+/// Without a token, the admin API is disabled.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct AdminConfig {
+    pub token: Option<String>,
+}
+
+/// Request guard admitting requests that bear the configured admin token.
+pub struct Admin;
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Admin {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        let config = request.rocket().state::<AdminConfig>();
+        let token = match config.and_then(|config| config.token.as_deref()) {
+            Some(token) if !token.is_empty() => token,
+            _ => return request::Outcome::Error((Status::NotFound, ())),
+        };
+
+        let given = request
+            .headers()
+            .get_one("Authorization")
+            .and_then(|header| header.strip_prefix("Bearer "));
+        match given {
+            Some(given) if constant_time_eq(given.as_bytes(), token.as_bytes()) => {
+                request::Outcome::Success(Admin)
+            }
+            _ => request::Outcome::Error((Status::Unauthorized, ())),
+        }
+    }
+}
+
+/// Compares tokens without giving away, by how long it takes, how much of
+/// them matched.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+/// The selection given by exactly one of the `url` and `prefix` parameters.
+/// An empty prefix, which would select everything, is refused.
+fn selection<'a>(url: Option<&'a str>, prefix: Option<&'a str>) -> Result<Selection<'a>, Status> {
+    match (url, prefix) {
+        (Some(url), None) => Ok(Selection::Url(url)),
+        (None, Some(prefix)) if !prefix.is_empty() => Ok(Selection::Prefix(prefix)),
+        _ => Err(Status::BadRequest),
+    }
+}
+
+fn json(value: Value) -> (ContentType, String) {
+    (ContentType::JSON, value.to_string())
+}
+
+pub fn routes() -> Vec<rocket::Route> {
+    routes![list, stats, purge, refresh]
+}
+
+pub fn catchers() -> Vec<rocket::Catcher> {
+    catchers![error]
+}
+
+/// Errors from the admin API are reported as JSON rather than as scields.
+#[catch(default)]
+fn error(status: Status, _: &Request) -> (ContentType, String) {
+    json(json!({
+        "error": status.reason_lossy().to_lowercase().replace(' ', "_"),
+    }))
+}
+
+/// The payloads held in memory, optionally only those of URLs starting with
+/// a prefix.
+#[get("/cache?<prefix>")]
+fn list(_admin: Admin, cache: &State<PayloadCache>, prefix: Option<&str>) -> (ContentType, String) {
+    let entries: Vec<_> = cache
+        .entries()
+        .into_iter()
+        .filter(|(url, _)| prefix.is_none_or(|prefix| url.starts_with(prefix)))
+        .map(|(url, entry)| {
+            json!({
+                "url": url,
+                "fetched_at": httpdate::fmt_http_date(entry.fetched_at),
+                "age": entry.age().as_secs(),
+                "error": entry.payload.err(),
+            })
+        })
+        .collect();
+    json(json!({ "entries": entries }))
+}
+
+#[get("/cache/stats")]
+fn stats(_admin: Admin, cache: &State<PayloadCache>) -> (ContentType, String) {
+    let entries = cache.entries();
+    let failures = entries
+        .iter()
+        .filter(|(_, entry)| entry.payload.is_err())
+        .count();
+    json(json!({
+        "entries": entries.len(),
+        "failures": failures,
+        "capacity": cache.capacity(),
+        "backend": cache.backend(),
+    }))
+}
+
+/// Purges the payload of a URL, or those of every URL starting with a prefix,
+/// from memory and the cache's store, so they're fetched afresh when next
+/// requested. Other instances sharing the store keep serving the copies they
+/// hold in memory until those expire.
+#[delete("/cache?<url>&<prefix>")]
+async fn purge(
+    _admin: Admin,
+    cache: &State<PayloadCache>,
+    url: Option<&str>,
+    prefix: Option<&str>,
+) -> Result<(ContentType, String), Status> {
+    let purged = cache.purge(selection(url, prefix)?).await;
+    Ok(json(json!({ "purged": purged })))
+}
+
+/// Fetches the payload of a URL afresh, whether or not it's cached, or those
+/// of every URL starting with a prefix that are held in memory. A few are
+/// fetched at a time, and, as when warming the cache, none are fetched from a
+/// host whose rate limit is down to the reserve kept for cache misses.
+#[post("/cache/refresh?<url>&<prefix>")]
+async fn refresh(
+    _admin: Admin,
+    client: &State<UpstreamClient>,
+    cache: &State<PayloadCache>,
+    in_flight: &State<InFlight>,
+    warmer: &State<Warmer>,
+    url: Option<&str>,
+    prefix: Option<&str>,
+) -> Result<(ContentType, String), Status> {
+    let urls: Vec<String> = match selection(url, prefix)? {
+        Selection::Url(url) => vec![url.to_string()],
+        selection => cache
+            .entries()
+            .into_iter()
+            .map(|(url, _)| url)
+            .filter(|url| selection.matches(url))
+            .collect(),
+    };
+
+    let refreshed: Vec<_> = stream::iter(urls)
+        .map(|url| refresh_url(client, cache, in_flight, warmer, url))
+        .buffered(REFRESH_CONCURRENCY)
+        .collect()
+        .await;
+    Ok(json(json!({ "refreshed": refreshed })))
+}
+
+/// Refreshes the payload of a URL, the way it was last fetched if it's been
+/// requested recently, such as by a GraphQL query, and otherwise from the URL
+/// itself, unless the rate limit that spends from is down to the reserve.
+async fn refresh_url(
+    client: &UpstreamClient,
+    cache: &PayloadCache,
+    in_flight: &InFlight,
+    warmer: &Warmer,
+    url: String,
+) -> Value {
+    let fetcher = warmer
+        .fetcher(&url)
+        .unwrap_or_else(|| Fetcher::get(ADMIN_SERVICE, &url));
+    let error = if fetcher
+        .headroom(client)
+        .is_some_and(|headroom| headroom < warmer.reserve())
+    {
+        Some(FetchError::Throttled)
+    } else {
+        fetcher.start(client, cache, in_flight).await.err()
+    };
+    json!({ "url": url, "error": error })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cache::CacheConfig;
+    use crate::client::{ClientConfig, Validators};
+    use crate::warming::WarmingConfig;
+    use rocket::http::Header;
+    use rocket::local::asynchronous::Client;
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+    use wiremock::matchers::path;
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    async fn client(token: Option<&str>) -> Client {
+        let rocket = rocket::build()
+            .manage(AdminConfig {
+                token: token.map(str::to_string),
+            })
+            .manage(UpstreamClient::new(ClientConfig::default(), vec![]).unwrap())
+            .manage(PayloadCache::new(CacheConfig::default()))
+            .manage(InFlight::default())
+            .manage(Warmer::new(WarmingConfig::default()))
+            .register("/admin", catchers())
+            .mount("/admin", routes());
+        Client::tracked(rocket).await.unwrap()
+    }
+
+    #[rocket::async_test]
+    async fn test_authentication() {
+        let client = client(None).await;
+        let response = client.get("/admin/cache/stats").dispatch().await;
+        assert_eq!(response.status(), Status::NotFound);
+
+        let client = self::client(Some("secret")).await;
+        let response = client.get("/admin/cache/stats").dispatch().await;
+        assert_eq!(response.status(), Status::Unauthorized);
+        assert_eq!(
+            response.into_string().await.unwrap(),
+            r#"{"error":"unauthorized"}"#
+        );
+
+        let response = client
+            .get("/admin/cache/stats")
+            .header(Header::new("Authorization", "Bearer wrong"))
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Unauthorized);
+
+        let response = client
+            .get("/admin/cache/stats")
+            .header(Header::new("Authorization", "Bearer secret"))
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Ok);
+    }
+
+    #[rocket::async_test]
+    async fn test_cache() {
+        let server = MockServer::start().await;
+        Mock::given(path("/repos/autophagy/scieldas"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({"stargazers_count": 2})))
+            .mount(&server)
+            .await;
+
+        let client = client(Some("secret")).await;
+        let cache = client.rocket().state::<PayloadCache>().unwrap();
+        let repo = format!("{}/repos/autophagy/scieldas", server.uri());
+        let issues = format!("{}/search/issues?q=repo:autophagy/scieldas", server.uri());
+        let other = format!("{}/repos/autophagy/other", server.uri());
+        for url in [&repo, &issues, &other] {
+            cache
+                .insert("test", url, Ok(json!(1)), Validators::default())
+                .await;
+        }
+        let admin = Header::new("Authorization", "Bearer secret");
+        let request = |method, uri: String| {
+            let request = client.req(method, uri).header(admin.clone());
+            async move {
+                let response = request.dispatch().await;
+                let status = response.status();
+                let body = response.into_string().await.unwrap();
+                (status, serde_json::from_str::<Value>(&body).unwrap())
+            }
+        };
+        let encode = |url: &str| rocket::http::RawStr::new(url).percent_encode().to_string();
+        use rocket::http::Method::{Delete, Get, Post};
+
+        let (_, stats) = request(Get, "/admin/cache/stats".to_string()).await;
+        assert_eq!(stats["entries"], 3);
+        assert_eq!(stats["backend"], "memory");
+
+        let uri = format!("/admin/cache?prefix={}", encode(&repo));
+        let (_, list) = request(Get, uri).await;
+        assert_eq!(list["entries"].as_array().unwrap().len(), 1);
+        assert_eq!(list["entries"][0]["url"], repo.as_str());
+
+        let uri = format!("/admin/cache/refresh?url={}", encode(&repo));
+        let (_, refreshed) = request(Post, uri).await;
+        assert_eq!(
+            refreshed,
+            json!({"refreshed": [{"url": repo, "error": null}]})
+        );
+        let payload = cache.last_good(&repo).unwrap().payload;
+        assert_eq!(payload, Ok(json!({"stargazers_count": 2})));
+
+        // Neither or both of a URL and a prefix are refused, as is an empty
+        // prefix.
+        let (status, _) = request(Delete, "/admin/cache".to_string()).await;
+        assert_eq!(status, Status::BadRequest);
+        let (status, _) = request(Post, "/admin/cache/refresh?prefix=".to_string()).await;
+        assert_eq!(status, Status::BadRequest);
+
+        let uri = format!("/admin/cache?url={}", encode(&other));
+        let (_, purged) = request(Delete, uri).await;
+        assert_eq!(purged, json!({"purged": 1}));
+
+        let prefix = format!("{}/", server.uri());
+        let uri = format!("/admin/cache?prefix={}", encode(&prefix));
+        let (_, purged) = request(Delete, uri).await;
+        assert_eq!(purged, json!({"purged": 2}));
+        assert!(cache.entries().is_empty());
+    }
+
+    #[rocket::async_test]
+    async fn test_refresh_keeps_reserve() {
+        let server = MockServer::start().await;
+        let reset = SystemTime::now() + Duration::from_secs(3600);
+        let reset = reset.duration_since(UNIX_EPOCH).unwrap().as_secs();
+        Mock::given(path("/repos/autophagy/scieldas"))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .set_body_json(json!({"stargazers_count": 2}))
+                    .insert_header("X-RateLimit-Remaining", "10")
+                    .insert_header("X-RateLimit-Reset", reset.to_string().as_str()),
+            )
+            .mount(&server)
+            .await;
+
+        let client = client(Some("secret")).await;
+        let repo = format!("{}/repos/autophagy/scieldas", server.uri());
+        let uri = format!(
+            "/admin/cache/refresh?url={}",
+            rocket::http::RawStr::new(&repo).percent_encode()
+        );
+        let refresh = || {
+            client
+                .post(uri.clone())
+                .header(Header::new("Authorization", "Bearer secret"))
+                .dispatch()
+        };
+
+        let refreshed = refresh().await.into_string().await.unwrap();
+        assert_eq!(
+            refreshed,
+            json!({"refreshed": [{"url": repo, "error": null}]}).to_string()
+        );
+        // The host has reported fewer requests left than are kept in reserve.
+        let refreshed = refresh().await.into_string().await.unwrap();
+        assert_eq!(
+            refreshed,
+            json!({"refreshed": [{"url": repo, "error": "Throttled"}]}).to_string()
+        );
+        assert_eq!(server.received_requests().await.unwrap().len(), 1);
+    }
+}
@@ -0,0 +1,185 @@
+use super::{CacheEntry, CacheStore, Selection};
+use rocket::tokio::task;
+use std::io;
+use std::path::Path;
+use std::time::Duration;
+
+/// A cache store kept in an embedded database on disk, so cached payloads
+/// survive restarts. Entries older than the retention period are treated as
+/// missing, and are purged whenever the store is opened.
+///
+/// The database blocks on disk, so it's used from the runtime's blocking
+/// threads rather than those answering requests.
+pub struct DiskStore {
+    db: sled::Db,
+    retention: Duration,
+}
+
+impl DiskStore {
+    pub fn open(path: &Path, retention: Duration) -> sled::Result<DiskStore> {
+        let store = DiskStore {
+            db: sled::open(path)?,
+            retention,
+        };
+        store.purge()?;
+        Ok(store)
+    }
+
+    fn decode(&self, bytes: &[u8]) -> Option<CacheEntry> {
+        serde_json::from_slice::<CacheEntry>(bytes)
+            .ok()
+            .filter(|entry| entry.age() < self.retention)
+    }
+
+    /// Runs a database operation on a blocking thread.
+    async fn blocking<T, F>(&self, operation: F) -> sled::Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&sled::Db) -> sled::Result<T> + Send + 'static,
+    {
+        let db = self.db.clone();
+        task::spawn_blocking(move || operation(&db))
+            .await
+            .unwrap_or_else(|e| Err(sled::Error::Io(io::Error::other(e))))
+    }
+
+    fn purge(&self) -> sled::Result<()> {
+        for item in self.db.iter() {
+            let (key, value) = item?;
+            if self.decode(&value).is_none() {
+                self.db.remove(key)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Writes out what's buffered, so that nothing is lost and the database's
+/// background writers are done with it, releasing its lock, once it's gone.
+impl Drop for DiskStore {
+    fn drop(&mut self) {
+        if let Err(e) = self.db.flush() {
+            log::warn!("Failed to flush the disk cache: {}", e);
+        }
+    }
+}
+
+#[rocket::async_trait]
+impl CacheStore for DiskStore {
+    async fn load(&self, url: &str) -> Option<CacheEntry> {
+        let key = url.to_string();
+        match self.blocking(move |db| db.get(key)).await {
+            Ok(value) => self.decode(&value?),
+            Err(e) => {
+                log::warn!("Failed to read {} from the disk cache: {}", url, e);
+                None
+            }
+        }
+    }
+
+    async fn save(&self, url: &str, entry: &CacheEntry) {
+        let value = serde_json::to_vec(entry).expect("cache entries serialize to JSON");
+        let key = url.to_string();
+        if let Err(e) = self.blocking(move |db| db.insert(key, value)).await {
+            log::warn!("Failed to write {} to the disk cache: {}", url, e);
+        }
+    }
+
+    async fn purge(&self, selection: Selection<'_>) -> Vec<String> {
+        let (selected, is_prefix) = match selection {
+            Selection::Url(url) => (url.to_string(), false),
+            Selection::Prefix(prefix) => (prefix.to_string(), true),
+        };
+        let purged = self
+            .blocking(move |db| {
+                let keys: Vec<sled::IVec> = if is_prefix {
+                    db.scan_prefix(&selected).keys().flatten().collect()
+                } else {
+                    vec![selected.as_str().into()]
+                };
+
+                let mut purged = Vec::new();
+                for key in keys {
+                    let url = String::from_utf8_lossy(&key).into_owned();
+                    match db.remove(key) {
+                        Ok(Some(_)) => purged.push(url),
+                        Ok(None) => {}
+                        Err(e) => log::warn!("Failed to purge {} from the disk cache: {}", url, e),
+                    }
+                }
+                Ok(purged)
+            })
+            .await;
+        purged.unwrap_or_default()
+    }
+
+    fn kind(&self) -> &'static str {
+        "disk"
+    }
+
+    async fn check(&self) -> Result<(), String> {
+        self.blocking(|db| db.first().map(drop))
+            .await
+            .map_err(|e| e.to_string())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::client::Validators;
+    use crate::utils::FetchError;
+    use serde_json::json;
+    use std::time::SystemTime;
+
+    fn copy_dir(from: &Path, to: &Path) {
+        std::fs::create_dir_all(to).unwrap();
+        for entry in std::fs::read_dir(from).unwrap() {
+            let entry = entry.unwrap();
+            let target = to.join(entry.file_name());
+            if entry.file_type().unwrap().is_dir() {
+                copy_dir(&entry.path(), &target);
+            } else {
+                std::fs::copy(entry.path(), target).unwrap();
+            }
+        }
+    }
+
+    #[rocket::async_test]
+    async fn test_survives_reopening() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("cache");
+        let retention = Duration::from_secs(3600);
+
+        let store = DiskStore::open(&path, retention).unwrap();
+        assert_eq!(store.check().await, Ok(()));
+        let fresh = CacheEntry {
+            payload: Ok(json!({"stargazers_count": 1})),
+            fetched_at: SystemTime::now(),
+            validators: Validators::default(),
+            retry_after: None,
+        };
+        let expired = CacheEntry {
+            payload: Err(FetchError::NotFound),
+            fetched_at: SystemTime::now() - Duration::from_secs(7200),
+            validators: Validators::default(),
+            retry_after: None,
+        };
+        store.save("fresh", &fresh).await;
+        store.save("expired", &expired).await;
+        assert!(store.load("expired").await.is_none());
+        drop(store);
+
+        // Sled's worker threads can still hold the database's lock for a
+        // moment after it's flushed and dropped, so what was written is
+        // reopened from a copy rather than waited on.
+        let copy = dir.path().join("copy");
+        copy_dir(&path, &copy);
+        let store = DiskStore::open(&copy, retention).unwrap();
+        let entry = store.load("fresh").await.unwrap();
+        assert_eq!(entry.payload, fresh.payload);
+        assert_eq!(entry.fetched_at, fresh.fetched_at);
+        assert!(!store.db.contains_key("expired").unwrap());
+        assert!(store.load("missing").await.is_none());
+    }
+}
@@ -0,0 +1,580 @@
+mod disk;
+mod redis;
+
+pub use self::redis::RedisStore;
+pub use disk::DiskStore;
+
+use crate::client::Validators;
+use crate::metrics::CACHE_EVICTIONS;
+use crate::utils::FetchError;
+use cached::{Cached, SizedCache};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+/// Configuration for the upstream payload cache, read from the `cache` table of
+/// the configuration, e.g.
+///
+/// ```toml
+/// [default.cache]
+/// size = 1000
+/// ttl = 300
+///
+/// [default.cache.ttl_overrides]
+/// github_latest_release = 3600
+/// github_stars = 60
+///
+/// [default.cache.negative_ttl]
+/// not_found = 300
+/// forbidden = 300
+/// rate_limited = 60
+/// decode = 60
+/// transient = 15
+///
+/// [default.cache.store]
+/// kind = "disk"
+/// path = "/var/cache/scieldas"
+/// ```
+///
+/// or, to share payloads between instances,
+///
+/// ```toml
+/// [default.cache.store]
+/// kind = "redis"
+/// url = "redis://127.0.0.1:6379/"
+/// ```
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct CacheConfig {
+    /// The maximum number of payloads held, after which the least recently
+    /// used are evicted.
+    pub size: usize,
+    /// How long, in seconds, a payload is considered fresh.
+    pub ttl: u64,
+    /// Per-service overrides of `ttl`, keyed by the service name passed to
+    /// `get_payload`.
+    pub ttl_overrides: HashMap<String, u64>,
+    /// How long, in seconds, past its TTL a payload may still be served while
+    /// it's refreshed in the background, or while the upstream is failing.
+    pub max_stale: u64,
+    pub negative_ttl: NegativeTtlConfig,
+    /// A persistent store backing the in-memory cache, if any.
+    pub store: Option<StoreConfig>,
+}
+
+/// How long, in seconds, failed fetches are cached for, by kind of failure.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct NegativeTtlConfig {
+    pub not_found: u64,
+    pub forbidden: u64,
+    pub rate_limited: u64,
+    pub decode: u64,
+    /// For upstream server errors, as well as network errors and timeouts.
+    pub transient: u64,
+}
+
+impl Default for NegativeTtlConfig {
+    fn default() -> NegativeTtlConfig {
+        NegativeTtlConfig {
+            not_found: 300,
+            forbidden: 300,
+            rate_limited: 60,
+            decode: 60,
+            transient: 15,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(tag = "kind", rename_all = "lowercase", deny_unknown_fields)]
+pub enum StoreConfig {
+    /// An embedded database in the given directory, surviving restarts.
+    Disk { path: PathBuf },
+    /// A Redis server at the given URL, shared between instances.
+    Redis { url: String },
+}
+
+impl Default for CacheConfig {
+    fn default() -> CacheConfig {
+        CacheConfig {
+            size: 1000,
+            ttl: 300,
+            ttl_overrides: HashMap::new(),
+            max_stale: 3600,
+            negative_ttl: NegativeTtlConfig::default(),
+            store: None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct CacheEntry {
+    pub payload: Result<Value, FetchError>,
+    pub fetched_at: SystemTime,
+    /// What the upstream identified the payload by, to revalidate it with.
+    #[serde(default)]
+    pub validators: Validators,
+    /// When the payload may next be refreshed, after a refresh of it failed.
+    #[serde(default)]
+    pub retry_after: Option<SystemTime>,
+}
+
+impl CacheEntry {
+    pub fn age(&self) -> Duration {
+        self.fetched_at.elapsed().unwrap_or_default()
+    }
+
+    /// Whether the payload may be refreshed, which it can't be for a while
+    /// after a refresh of it failed.
+    pub fn refreshable(&self) -> bool {
+        self.retry_after
+            .is_none_or(|retry_after| retry_after <= SystemTime::now())
+    }
+}
+
+/// A persistent store of cache entries, sitting behind the in-memory cache.
+/// Stores are expected to handle their own errors, treating entries they
+/// can't read as missing.
+#[rocket::async_trait]
+pub trait CacheStore: Send + Sync {
+    async fn load(&self, url: &str) -> Option<CacheEntry>;
+
+    async fn save(&self, url: &str, entry: &CacheEntry);
+
+    /// Removes the selected entries, returning the URLs of those removed.
+    async fn purge(&self, selection: Selection<'_>) -> Vec<String>;
+
+    /// What kind of store it is, such as `"disk"`.
+    fn kind(&self) -> &'static str;
+
+    /// Checks that the store can be used, returning why not if it can't.
+    async fn check(&self) -> Result<(), String>;
+}
+
+/// Which cached payloads to act on: that of a single URL, or those of every
+/// URL starting with a prefix. Prefixes are matched as plain text, so
+/// `.../repos/owner/repo` also selects `.../repos/owner/repo-other`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Selection<'a> {
+    Url(&'a str),
+    Prefix(&'a str),
+}
+
+impl Selection<'_> {
+    pub fn matches(&self, url: &str) -> bool {
+        match self {
+            Selection::Url(selected) => url == *selected,
+            Selection::Prefix(prefix) => url.starts_with(prefix),
+        }
+    }
+}
+
+pub enum Lookup {
+    Fresh(CacheEntry),
+    /// The entry is past its TTL, but within the maximum staleness and so can
+    /// still be served while it's refreshed.
+    Stale(CacheEntry),
+    Miss,
+}
+
+/// An in-memory cache of upstream payloads, keyed by URL, optionally backed by
+/// a persistent store. Entries missing from memory are looked up in the store,
+/// and new entries are written through to it.
+///
+/// Entries don't carry their own expiry. Instead, whether an entry is still
+/// fresh is decided when it's read, using the TTL of the service reading it,
+/// so routes sharing an upstream URL can tolerate different staleness.
+///
+/// The cache is cheap to clone, with clones sharing the same entries, so it
+/// can be handed to background refreshes.
+#[derive(Clone)]
+pub struct PayloadCache {
+    config: Arc<CacheConfig>,
+    entries: Arc<Mutex<SizedCache<String, CacheEntry>>>,
+    store: Option<Arc<dyn CacheStore>>,
+}
+
+impl PayloadCache {
+    pub fn new(config: CacheConfig) -> PayloadCache {
+        PayloadCache {
+            entries: Arc::new(Mutex::new(SizedCache::with_size(config.size.max(1)))),
+            config: Arc::new(config),
+            store: None,
+        }
+    }
+
+    pub fn with_store(self, store: Arc<dyn CacheStore>) -> PayloadCache {
+        PayloadCache {
+            store: Some(store),
+            ..self
+        }
+    }
+
+    /// What kind of store backs the cache, or `"memory"` if there's none.
+    pub fn backend(&self) -> &'static str {
+        self.store.as_ref().map_or("memory", |store| store.kind())
+    }
+
+    /// Checks that the store backing the cache, if any, can be used.
+    pub async fn check(&self) -> Result<(), String> {
+        match &self.store {
+            Some(store) => store.check().await,
+            None => Ok(()),
+        }
+    }
+
+    /// How many entries can be held in memory.
+    pub fn capacity(&self) -> usize {
+        self.config.size.max(1)
+    }
+
+    /// The entries held in memory, ordered by URL.
+    pub fn entries(&self) -> Vec<(String, CacheEntry)> {
+        let entries = self.entries.lock().unwrap();
+        let mut entries: Vec<_> = entries
+            .key_order()
+            .cloned()
+            .zip(entries.value_order().cloned())
+            .collect();
+        entries.sort_unstable_by(|a, b| a.0.cmp(&b.0));
+        entries
+    }
+
+    /// Removes the selected entries, from memory and the store, returning
+    /// how many URLs had entries removed.
+    pub async fn purge(&self, selection: Selection<'_>) -> usize {
+        let mut purged: Vec<String> = {
+            let mut entries = self.entries.lock().unwrap();
+            let urls: Vec<String> = entries
+                .key_order()
+                .filter(|url| selection.matches(url))
+                .cloned()
+                .collect();
+            for url in &urls {
+                entries.cache_remove(url);
+            }
+            urls
+        };
+        if let Some(store) = &self.store {
+            purged.extend(store.purge(selection).await);
+        }
+        purged.sort_unstable();
+        purged.dedup();
+        purged.len()
+    }
+
+    /// The longest any entry could still be served for, after which it can
+    /// be dropped from persistent stores.
+    pub fn retention(&self) -> Duration {
+        let config = &self.config;
+        let ttl = config
+            .ttl_overrides
+            .values()
+            .fold(config.ttl, |a, b| a.max(*b));
+        let negative = &config.negative_ttl;
+        let negative_ttl = [
+            negative.not_found,
+            negative.forbidden,
+            negative.rate_limited,
+            negative.decode,
+            negative.transient,
+        ]
+        .into_iter()
+        .fold(0, u64::max);
+        Duration::from_secs((ttl + config.max_stale).max(negative_ttl))
+    }
+
+    /// The TTL for payloads read by the given service.
+    pub fn ttl(&self, service: &str) -> Duration {
+        let ttl = self
+            .config
+            .ttl_overrides
+            .get(service)
+            .unwrap_or(&self.config.ttl);
+        Duration::from_secs(*ttl)
+    }
+
+    fn max_age(&self, service: &str) -> Duration {
+        self.ttl(service) + Duration::from_secs(self.config.max_stale)
+    }
+
+    /// The TTL for failed fetches of the given kind.
+    pub fn negative_ttl(&self, error: FetchError) -> Duration {
+        let ttl = &self.config.negative_ttl;
+        Duration::from_secs(match error {
+            FetchError::NotFound => ttl.not_found,
+            FetchError::Forbidden => ttl.forbidden,
+            FetchError::RateLimited => ttl.rate_limited,
+            FetchError::Decode => ttl.decode,
+            FetchError::ServerError | FetchError::Transient => ttl.transient,
+            FetchError::Throttled => 0,
+        })
+    }
+
+    /// Looks up the cached payload for a URL, judging its freshness by the TTL
+    /// of the given service, or for failed fetches by the negative TTL of the
+    /// failure. Failures are never served stale.
+    pub async fn get(&self, service: &str, url: &str) -> Lookup {
+        let entry = self
+            .entries
+            .lock()
+            .unwrap()
+            .cache_get(&url.to_string())
+            .cloned();
+
+        let entry = match (entry, &self.store) {
+            (Some(entry), _) => entry,
+            (None, Some(store)) => match store.load(url).await {
+                Some(entry) => {
+                    hold(&mut self.entries.lock().unwrap(), url, entry.clone());
+                    entry
+                }
+                None => return Lookup::Miss,
+            },
+            (None, None) => return Lookup::Miss,
+        };
+
+        match entry.payload {
+            Ok(_) if entry.age() < self.ttl(service) => Lookup::Fresh(entry),
+            Ok(_) if entry.age() < self.max_age(service) => Lookup::Stale(entry),
+            Err(e) if entry.age() < self.negative_ttl(e) => Lookup::Fresh(entry),
+            _ => Lookup::Miss,
+        }
+    }
+
+    /// How long until the payload held in memory for a URL expires, judged by
+    /// the TTL of the given service, or `None` if there's no payload held. A
+    /// payload whose refresh failed isn't counted as expiring until it may be
+    /// refreshed again.
+    pub fn expires_in(&self, service: &str, url: &str) -> Option<Duration> {
+        let mut entries = self.entries.lock().unwrap();
+        let entry = entries.cache_get(&url.to_string())?;
+        let ttl = match entry.payload {
+            Ok(_) => self.ttl(service),
+            Err(e) => self.negative_ttl(e),
+        };
+        let retry_in = entry
+            .retry_after
+            .and_then(|retry_after| retry_after.duration_since(SystemTime::now()).ok())
+            .unwrap_or_default();
+        Some(ttl.saturating_sub(entry.age()).max(retry_in))
+    }
+
+    /// The last payload successfully fetched from a URL, if it's still held in
+    /// memory, whatever its age.
+    pub fn last_good(&self, url: &str) -> Option<CacheEntry> {
+        let mut entries = self.entries.lock().unwrap();
+        entries
+            .cache_get(&url.to_string())
+            .filter(|entry| entry.payload.is_ok())
+            .cloned()
+    }
+
+    /// Stores a newly fetched, or revalidated, payload along with what the
+    /// upstream identified it by. A failed fetch doesn't replace a payload that
+    /// can still be served as stale, so the last known good value outlives
+    /// upstream errors. Instead, the payload isn't refreshed again until the
+    /// failure's negative TTL has passed.
+    pub async fn insert(
+        &self,
+        service: &str,
+        url: &str,
+        payload: Result<Value, FetchError>,
+        validators: Validators,
+    ) {
+        let entry = {
+            let mut entries = self.entries.lock().unwrap();
+
+            let servable = entries
+                .cache_get(&url.to_string())
+                .filter(|entry| entry.payload.is_ok() && entry.age() < self.max_age(service))
+                .cloned();
+            let entry = match (payload, servable) {
+                (Err(e), Some(servable)) => CacheEntry {
+                    retry_after: Some(SystemTime::now() + self.negative_ttl(e)),
+                    ..servable
+                },
+                (payload, _) => CacheEntry {
+                    payload,
+                    fetched_at: SystemTime::now(),
+                    validators,
+                    retry_after: None,
+                },
+            };
+            hold(&mut entries, url, entry.clone());
+            entry
+        };
+
+        if let Some(store) = &self.store {
+            store.save(url, &entry).await;
+        }
+    }
+}
+
+/// Holds an entry in memory, counting the entry evicted to make room for it,
+/// if any.
+fn hold(entries: &mut SizedCache<String, CacheEntry>, url: &str, entry: CacheEntry) {
+    let size = entries.cache_size();
+    let replaced = entries.cache_set(url.to_string(), entry).is_some();
+    let evicted = size + usize::from(!replaced) - entries.cache_size();
+    CACHE_EVICTIONS.inc_by(evicted as u64);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+
+    fn backdate(cache: &PayloadCache, url: &str, age: u64) {
+        let mut entries = cache.entries.lock().unwrap();
+        let entry = entries.cache_get_mut(&url.to_string()).unwrap();
+        entry.fetched_at -= Duration::from_secs(age);
+    }
+
+    #[rocket::async_test]
+    async fn test_ttl_overrides() {
+        let config = CacheConfig {
+            ttl_overrides: HashMap::from([("github_latest_release".to_string(), 3600)]),
+            ..CacheConfig::default()
+        };
+        let cache = PayloadCache::new(config);
+        assert_eq!(cache.ttl("github_stars"), Duration::from_secs(300));
+        assert_eq!(
+            cache.ttl("github_latest_release"),
+            Duration::from_secs(3600)
+        );
+
+        cache
+            .insert(
+                "github_stars",
+                "https://example.com",
+                Ok(json!({"stars": 1})),
+                Validators::default(),
+            )
+            .await;
+        assert!(matches!(
+            cache.get("github_stars", "https://example.com").await,
+            Lookup::Fresh(_)
+        ));
+
+        backdate(&cache, "https://example.com", 600);
+        assert!(matches!(
+            cache.get("github_stars", "https://example.com").await,
+            Lookup::Stale(_)
+        ));
+        assert!(matches!(
+            cache
+                .get("github_latest_release", "https://example.com")
+                .await,
+            Lookup::Fresh(_)
+        ));
+
+        backdate(&cache, "https://example.com", 3600);
+        assert!(matches!(
+            cache.get("github_stars", "https://example.com").await,
+            Lookup::Miss
+        ));
+    }
+
+    #[rocket::async_test]
+    async fn test_keeps_last_known_good() {
+        let cache = PayloadCache::new(CacheConfig::default());
+        cache
+            .insert(
+                "github_stars",
+                "https://example.com",
+                Ok(json!(1)),
+                Validators::default(),
+            )
+            .await;
+        backdate(&cache, "https://example.com", 600);
+
+        cache
+            .insert(
+                "github_stars",
+                "https://example.com",
+                Err(FetchError::Transient),
+                Validators::default(),
+            )
+            .await;
+        match cache.get("github_stars", "https://example.com").await {
+            Lookup::Stale(entry) => assert_eq!(entry.payload, Ok(json!(1))),
+            _ => panic!("expected the last known good payload"),
+        }
+
+        backdate(&cache, "https://example.com", 3600);
+        cache
+            .insert(
+                "github_stars",
+                "https://example.com",
+                Err(FetchError::Transient),
+                Validators::default(),
+            )
+            .await;
+        match cache.get("github_stars", "https://example.com").await {
+            Lookup::Fresh(entry) => assert_eq!(entry.payload, Err(FetchError::Transient)),
+            _ => panic!("expected the failed fetch to be cached"),
+        }
+    }
+
+    #[rocket::async_test]
+    async fn test_negative_ttl() {
+        let cache = PayloadCache::new(CacheConfig::default());
+        cache
+            .insert(
+                "github_stars",
+                "not_found",
+                Err(FetchError::NotFound),
+                Validators::default(),
+            )
+            .await;
+        cache
+            .insert(
+                "github_stars",
+                "transient",
+                Err(FetchError::Transient),
+                Validators::default(),
+            )
+            .await;
+
+        backdate(&cache, "not_found", 60);
+        backdate(&cache, "transient", 60);
+        assert!(matches!(
+            cache.get("github_stars", "not_found").await,
+            Lookup::Fresh(_)
+        ));
+        assert!(matches!(
+            cache.get("github_stars", "transient").await,
+            Lookup::Miss
+        ));
+
+        backdate(&cache, "not_found", 300);
+        assert!(matches!(
+            cache.get("github_stars", "not_found").await,
+            Lookup::Miss
+        ));
+    }
+
+    #[rocket::async_test]
+    async fn test_size() {
+        let cache = PayloadCache::new(CacheConfig {
+            size: 2,
+            ..CacheConfig::default()
+        });
+        cache
+            .insert("github_stars", "a", Ok(Value::Null), Validators::default())
+            .await;
+        cache
+            .insert("github_stars", "b", Ok(Value::Null), Validators::default())
+            .await;
+        cache
+            .insert("github_stars", "c", Ok(Value::Null), Validators::default())
+            .await;
+        let entries = cache.entries.lock().unwrap();
+        assert_eq!(entries.key_order().collect::<Vec<_>>(), vec!["c", "b"]);
+    }
+}
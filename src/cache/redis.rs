@@ -0,0 +1,255 @@
+use super::{CacheEntry, CacheStore, Selection};
+use ::redis::aio::ConnectionManager;
+use ::redis::{AsyncCommands, Client, ErrorKind, RedisResult};
+use rocket::tokio::time;
+use std::future::Future;
+use std::sync::{Arc, OnceLock, Weak};
+use std::time::Duration;
+
+const KEY_PREFIX: &str = "scieldas:";
+
+/// How long to wait for Redis to connect.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long to wait for Redis to answer a command, after which it's treated
+/// as having failed, so that a stalled Redis doesn't stall cache misses.
+const COMMAND_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// How long to wait between attempts to connect to a Redis that couldn't be
+/// reached.
+const RECONNECT_INTERVAL: Duration = Duration::from_secs(5);
+
+/// A cache store kept in Redis, or anything speaking its protocol, so that
+/// several instances behind a load balancer share their payloads. Entries are
+/// written with an expiry of the retention period, leaving Redis to drop them.
+///
+/// If Redis can't be reached at first, connecting is retried in the
+/// background, and the connection is re-established if it's lost. Until then,
+/// reads miss and writes are dropped, leaving each instance with its in-memory
+/// cache.
+pub struct RedisStore {
+    connection: Arc<OnceLock<ConnectionManager>>,
+    retention: Duration,
+}
+
+impl RedisStore {
+    /// Opens a store in the Redis at the given URL, failing only if the URL
+    /// is invalid.
+    pub async fn connect(url: &str, retention: Duration) -> RedisResult<RedisStore> {
+        let client = Client::open(url)?;
+        let connection = Arc::new(OnceLock::new());
+        if let Err(e) = establish(&client, &connection).await {
+            log::warn!(
+                "Failed to connect to Redis, caching in memory only until it can be: {}",
+                e
+            );
+            rocket::tokio::spawn(reconnect(client, Arc::downgrade(&connection)));
+        }
+        Ok(RedisStore {
+            connection,
+            retention,
+        })
+    }
+
+    fn connection(&self) -> RedisResult<ConnectionManager> {
+        self.connection
+            .get()
+            .cloned()
+            .ok_or_else(|| (ErrorKind::IoError, "not connected").into())
+    }
+
+    fn key(url: &str) -> String {
+        format!("{}{}", KEY_PREFIX, url)
+    }
+
+    /// The keys of entries whose URLs start with a prefix.
+    async fn scan(&self, prefix: &str) -> RedisResult<Vec<String>> {
+        let mut pattern = String::new();
+        for c in Self::key(prefix).chars() {
+            if matches!(c, '*' | '?' | '[' | ']' | '\\') {
+                pattern.push('\\');
+            }
+            pattern.push(c);
+        }
+        pattern.push('*');
+
+        let mut connection = self.connection()?;
+        command(async move {
+            let mut keys = connection.scan_match::<_, String>(pattern).await?;
+            let mut scanned = Vec::new();
+            while let Some(key) = keys.next_item().await {
+                scanned.push(key);
+            }
+            Ok(scanned)
+        })
+        .await
+    }
+}
+
+async fn establish(client: &Client, connection: &OnceLock<ConnectionManager>) -> RedisResult<()> {
+    let manager = time::timeout(CONNECT_TIMEOUT, client.get_connection_manager())
+        .await
+        .unwrap_or_else(|_| Err((ErrorKind::IoError, "timed out").into()))?;
+    let _ = connection.set(manager);
+    Ok(())
+}
+
+/// Tries to connect until it succeeds, or the store is dropped.
+async fn reconnect(client: Client, connection: Weak<OnceLock<ConnectionManager>>) {
+    loop {
+        time::sleep(RECONNECT_INTERVAL).await;
+        let Some(connection) = connection.upgrade() else {
+            return;
+        };
+        if establish(&client, &connection).await.is_ok() {
+            log::info!("Connected to Redis");
+            return;
+        }
+    }
+}
+
+/// Runs a command, failing it if Redis takes too long to answer.
+async fn command<T>(command: impl Future<Output = RedisResult<T>>) -> RedisResult<T> {
+    time::timeout(COMMAND_TIMEOUT, command)
+        .await
+        .unwrap_or_else(|_| Err((ErrorKind::IoError, "timed out").into()))
+}
+
+#[rocket::async_trait]
+impl CacheStore for RedisStore {
+    async fn load(&self, url: &str) -> Option<CacheEntry> {
+        let value = match self.connection() {
+            Ok(mut connection) => {
+                command(connection.get::<_, Option<Vec<u8>>>(Self::key(url))).await
+            }
+            Err(e) => Err(e),
+        };
+        match value {
+            Ok(value) => serde_json::from_slice::<CacheEntry>(&value?)
+                .ok()
+                .filter(|entry| entry.age() < self.retention),
+            Err(e) => {
+                log::warn!("Failed to read {} from Redis: {}", url, e);
+                None
+            }
+        }
+    }
+
+    async fn save(&self, url: &str, entry: &CacheEntry) {
+        let value = serde_json::to_vec(entry).expect("cache entries serialize to JSON");
+        let expiry = self.retention.as_secs().max(1) as usize;
+        let saved = match self.connection() {
+            Ok(mut connection) => {
+                command(connection.set_ex::<_, _, ()>(Self::key(url), value, expiry)).await
+            }
+            Err(e) => Err(e),
+        };
+        if let Err(e) = saved {
+            log::warn!("Failed to write {} to Redis: {}", url, e);
+        }
+    }
+
+    async fn purge(&self, selection: Selection<'_>) -> Vec<String> {
+        let keys = match selection {
+            Selection::Url(url) => vec![Self::key(url)],
+            Selection::Prefix(prefix) => match self.scan(prefix).await {
+                Ok(keys) => keys,
+                Err(e) => {
+                    log::warn!("Failed to find entries under {} in Redis: {}", prefix, e);
+                    return Vec::new();
+                }
+            },
+        };
+
+        let mut connection = match self.connection() {
+            Ok(connection) => connection,
+            Err(e) => {
+                log::warn!("Failed to purge entries from Redis: {}", e);
+                return Vec::new();
+            }
+        };
+        let mut purged = Vec::new();
+        for key in keys {
+            let url = key[KEY_PREFIX.len()..].to_string();
+            match command(connection.del::<_, usize>(&key)).await {
+                Ok(0) => {}
+                Ok(_) => purged.push(url),
+                Err(e) => log::warn!("Failed to purge {} from Redis: {}", url, e),
+            }
+        }
+        purged
+    }
+
+    fn kind(&self) -> &'static str {
+        "redis"
+    }
+
+    async fn check(&self) -> Result<(), String> {
+        let mut connection = self.connection().map_err(|e| e.to_string())?;
+        command(::redis::cmd("PING").query_async::<_, ()>(&mut connection))
+            .await
+            .map_err(|e| e.to_string())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::client::Validators;
+    use crate::utils::FetchError;
+    use serde_json::json;
+    use std::time::SystemTime;
+
+    #[rocket::async_test]
+    async fn test_unreachable() {
+        let invalid = RedisStore::connect("http://127.0.0.1/", Duration::from_secs(60)).await;
+        assert!(invalid.is_err());
+
+        // Nothing listens on port 1, so the store works as if it were empty
+        // until something does.
+        let store = RedisStore::connect("redis://127.0.0.1:1/", Duration::from_secs(60))
+            .await
+            .unwrap();
+        assert!(store.check().await.is_err());
+        assert!(store.load("missing").await.is_none());
+    }
+
+    // Needs a running Redis, given by the REDIS_URL environment variable, e.g.
+    // REDIS_URL=redis://127.0.0.1:6379/ cargo test, and is skipped without one.
+    #[rocket::async_test]
+    async fn test_shared_between_stores() {
+        let url = match std::env::var("REDIS_URL") {
+            Ok(url) => url,
+            Err(_) => {
+                eprintln!("Skipping test_shared_between_stores, as REDIS_URL isn't set");
+                return;
+            }
+        };
+        let retention = Duration::from_secs(3600);
+        let a = RedisStore::connect(&url, retention).await.unwrap();
+        let b = RedisStore::connect(&url, retention).await.unwrap();
+        assert_eq!(a.check().await, Ok(()));
+        let key = format!("test-{}", std::process::id());
+
+        let entry = CacheEntry {
+            payload: Ok(json!({"stargazers_count": 1})),
+            fetched_at: SystemTime::now(),
+            validators: Validators::default(),
+            retry_after: None,
+        };
+        a.save(&key, &entry).await;
+        let loaded = b.load(&key).await.unwrap();
+        assert_eq!(loaded.payload, entry.payload);
+        assert_eq!(loaded.fetched_at, entry.fetched_at);
+
+        let expired = CacheEntry {
+            payload: Err(FetchError::NotFound),
+            fetched_at: SystemTime::now() - Duration::from_secs(7200),
+            validators: Validators::default(),
+            retry_after: None,
+        };
+        a.save(&key, &expired).await;
+        assert!(b.load(&key).await.is_none());
+        assert!(b.load("missing").await.is_none());
+    }
+}
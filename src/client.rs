@@ -0,0 +1,827 @@
+use crate::metrics::{UPSTREAM_ERRORS, UPSTREAM_SECONDS};
+use crate::tokens::TokenPool;
+use crate::utils::FetchError;
+use rand::Rng;
+use reqwest::header::{
+    HeaderMap, ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED, RETRY_AFTER,
+};
+use reqwest::{Client, Response, StatusCode, Url};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// How long to back off for when an upstream limits us without saying until
+/// when.
+const DEFAULT_BACKOFF: Duration = Duration::from_secs(60);
+
+/// How long whether an upstream is reachable is remembered for, so that
+/// frequent readiness probes don't query upstreams each time.
+const PROBE_TTL: Duration = Duration::from_secs(30);
+
+/// Configuration for upstream requests, read from the `upstream` table of
+/// the configuration, e.g.
+///
+/// ```toml
+/// [default.upstream]
+/// connect_timeout_ms = 2000
+/// timeout_ms = 5000
+/// retries = 2
+/// backoff_ms = 200
+/// ```
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct ClientConfig {
+    /// How long to wait for a connection to an upstream.
+    pub connect_timeout_ms: u64,
+    /// How long to wait for a whole request, from connecting to reading the
+    /// last of the response.
+    pub timeout_ms: u64,
+    /// How many times to retry requests that fail to connect, time out or
+    /// meet an upstream server error.
+    pub retries: u32,
+    /// The delay before the first retry, doubling with each retry after it.
+    pub backoff_ms: u64,
+}
+
+impl Default for ClientConfig {
+    fn default() -> ClientConfig {
+        ClientConfig {
+            connect_timeout_ms: 2000,
+            timeout_ms: 5000,
+            retries: 2,
+            backoff_ms: 200,
+        }
+    }
+}
+
+/// What an upstream identified a payload by, with its `ETag` and
+/// `Last-Modified` headers, so that it can be revalidated with a conditional
+/// request rather than fetched again.
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq, Eq)]
+pub struct Validators {
+    pub etag: Option<String>,
+    pub last_modified: Option<String>,
+}
+
+impl Validators {
+    fn of(headers: &HeaderMap) -> Validators {
+        let header = |name| {
+            headers
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .map(str::to_string)
+        };
+        Validators {
+            etag: header(ETAG),
+            last_modified: header(LAST_MODIFIED),
+        }
+    }
+}
+
+/// The outcome of a successful fetch.
+#[derive(Debug, PartialEq)]
+pub enum Fetched {
+    Modified(Value, Validators),
+    /// The payload identified by the validators sent is still current.
+    NotModified,
+}
+
+/// The client used to fetch upstream payloads. It authenticates with tokens
+/// from the pool where they cover the URL, and keeps track of the rate limits
+/// upstreams report, so that it doesn't make requests that are bound to be
+/// refused until the limit resets.
+///
+/// The client is cheap to clone, with clones sharing the same state.
+#[derive(Clone)]
+pub struct UpstreamClient {
+    client: Client,
+    config: Arc<ClientConfig>,
+    tokens: Arc<[TokenPool]>,
+    limits: RateLimits,
+    /// Whether each URL probed was reachable, and when it was probed.
+    probes: Arc<Mutex<HashMap<String, (Instant, bool)>>>,
+}
+
+/// The rate limit a request spends from, if the host has separate ones, such
+/// as GitHub's `core`, `search` and `graphql` limits.
+const DEFAULT_RESOURCE: &str = "core";
+
+/// The rate limit queries spend from, going by GitHub's GraphQL API.
+const QUERY_RESOURCE: &str = "graphql";
+
+/// A host, the token used for requests to it, if any, and the limit spent.
+type LimitKey = (String, Option<String>, String);
+
+/// What a host last reported about one of its rate limits for a token.
+#[derive(Clone, Copy, Default)]
+struct Limit {
+    /// When requests can be made again, if we're backing off.
+    until: Option<SystemTime>,
+    /// How many requests are left, and when that count resets.
+    remaining: Option<(u64, SystemTime)>,
+}
+
+/// The rate limits of each host, per token and resource.
+#[derive(Clone, Default)]
+struct RateLimits {
+    limits: Arc<Mutex<HashMap<LimitKey, Limit>>>,
+}
+
+impl RateLimits {
+    fn get(&self, host: &str, token: Option<&str>, resource: &str) -> Limit {
+        let key = (
+            host.to_string(),
+            token.map(str::to_string),
+            resource.to_string(),
+        );
+        let limits = self.limits.lock().unwrap();
+        limits.get(&key).copied().unwrap_or_default()
+    }
+
+    /// The hosts that have reported their rate limits, with each resource
+    /// they've reported on.
+    fn resources(&self) -> Vec<(String, String)> {
+        let limits = self.limits.lock().unwrap();
+        let mut resources: Vec<_> = limits
+            .keys()
+            .map(|(host, _, resource)| (host.clone(), resource.clone()))
+            .collect();
+        resources.sort_unstable();
+        resources.dedup();
+        resources
+    }
+
+    fn is_limited(&self, host: &str, token: Option<&str>, resource: &str) -> bool {
+        match self.get(host, token, resource).until {
+            Some(until) => until > SystemTime::now(),
+            None => false,
+        }
+    }
+
+    /// How many requests are known to be left for a host, token and resource,
+    /// if the host has said and the count hasn't reset since.
+    fn remaining(&self, host: &str, token: Option<&str>, resource: &str) -> Option<u64> {
+        if self.is_limited(host, token, resource) {
+            return Some(0);
+        }
+        let (remaining, resets) = self.get(host, token, resource).remaining?;
+        (resets > SystemTime::now()).then_some(remaining)
+    }
+
+    /// Updates the limit on a host and token from the headers of a response,
+    /// returning whether requests to it are now limited. The limit updated is
+    /// the one the response names in `X-RateLimit-Resource`, if any, rather
+    /// than the one the request was expected to spend from.
+    fn update(&self, host: &str, token: Option<&str>, resource: &str, response: &Response) -> bool {
+        let headers = response.headers();
+        let resource = headers
+            .get("x-ratelimit-resource")
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or(resource);
+        let key = (
+            host.to_string(),
+            token.map(str::to_string),
+            resource.to_string(),
+        );
+        let until = limited_until(response.status(), headers);
+        if let Some(time) = until {
+            log::warn!(
+                "Rate limited by {} for {}, backing off until {}",
+                host,
+                resource,
+                httpdate::fmt_http_date(time)
+            );
+        }
+
+        let header = |name| {
+            headers
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| value.parse().ok())
+        };
+        let remaining = match (header("x-ratelimit-remaining"), header("x-ratelimit-reset")) {
+            (Some(remaining), Some(reset)) => {
+                Some((remaining, UNIX_EPOCH + Duration::from_secs(reset)))
+            }
+            _ => None,
+        };
+
+        let mut limits = self.limits.lock().unwrap();
+        limits.insert(key, Limit { until, remaining });
+        until.is_some()
+    }
+}
+
+/// The rate limit a request is expected to spend from, going by GitHub's,
+/// until the response says otherwise.
+fn resource_of(url: &str, body: Option<&Value>) -> &'static str {
+    let is_search = Url::parse(url)
+        .map(|url| url.path().contains("/search/"))
+        .unwrap_or(false);
+    match body {
+        Some(_) => QUERY_RESOURCE,
+        None if is_search => "search",
+        None => DEFAULT_RESOURCE,
+    }
+}
+
+fn host_of(url: &str) -> String {
+    Url::parse(url)
+        .ok()
+        .and_then(|url| url.host_str().map(str::to_string))
+        .unwrap_or_default()
+}
+
+/// When a response says requests can be made again, if it's a refusal or
+/// uses up the last of the rate limit. `Retry-After` takes precedence over the
+/// `X-RateLimit-*` headers GitHub uses.
+///
+/// A time that has already passed, from `Retry-After: 0` or a clock skewed
+/// against the upstream's, is backed off from as if none was given, or the
+/// request would be made again at once for as long as it's refused.
+fn limited_until(status: StatusCode, headers: &HeaderMap) -> Option<SystemTime> {
+    let now = SystemTime::now();
+    let until = said_until(status, headers, now)?;
+    Some(if until > now {
+        until
+    } else {
+        now + DEFAULT_BACKOFF
+    })
+}
+
+fn said_until(status: StatusCode, headers: &HeaderMap, now: SystemTime) -> Option<SystemTime> {
+    let header = |name| headers.get(name).and_then(|value| value.to_str().ok());
+
+    if let Some(retry_after) = header(RETRY_AFTER.as_str()) {
+        return match retry_after.parse() {
+            Ok(secs) => Some(now + Duration::from_secs(secs)),
+            Err(_) => httpdate::parse_http_date(retry_after).ok(),
+        };
+    }
+
+    if header("x-ratelimit-remaining") == Some("0") {
+        if let Some(reset) = header("x-ratelimit-reset").and_then(|r| r.parse().ok()) {
+            return Some(UNIX_EPOCH + Duration::from_secs(reset));
+        }
+    }
+
+    match status {
+        StatusCode::TOO_MANY_REQUESTS => Some(now + DEFAULT_BACKOFF),
+        _ => None,
+    }
+}
+
+impl UpstreamClient {
+    pub fn new(config: ClientConfig, tokens: Vec<TokenPool>) -> reqwest::Result<UpstreamClient> {
+        let client = Client::builder()
+            .user_agent("scieldas")
+            .connect_timeout(Duration::from_millis(config.connect_timeout_ms))
+            .timeout(Duration::from_millis(config.timeout_ms))
+            .build()?;
+
+        Ok(UpstreamClient {
+            client,
+            config: Arc::new(config),
+            tokens: tokens.into(),
+            limits: RateLimits::default(),
+            probes: Arc::default(),
+        })
+    }
+
+    /// The delay before the given retry, doubling with each retry and jittered
+    /// to between half and all of that so retries don't arrive in lockstep.
+    fn backoff(&self, retry: u32) -> Duration {
+        let delay = self.config.backoff_ms.saturating_mul(1 << retry.min(16));
+        Duration::from_millis(rand::thread_rng().gen_range(delay / 2..=delay))
+    }
+
+    /// Sends a GET request conditional on the validators given, or a POST of
+    /// the JSON body given, retrying with backoff if it fails to connect, times
+    /// out or meets a server error. Only POSTs of queries, which are as safe
+    /// to repeat as a GET, should be sent.
+    async fn send(
+        &self,
+        url: &str,
+        token: Option<&str>,
+        validators: &Validators,
+        body: Option<&Value>,
+    ) -> Result<Response, FetchError> {
+        let mut retry = 0;
+        loop {
+            let mut request = match body {
+                Some(body) => self.client.post(url).json(body),
+                None => self.client.get(url),
+            };
+            if let Some(token) = token {
+                request = request.bearer_auth(token);
+            }
+            if let Some(etag) = &validators.etag {
+                request = request.header(IF_NONE_MATCH, etag);
+            }
+            if let Some(last_modified) = &validators.last_modified {
+                request = request.header(IF_MODIFIED_SINCE, last_modified);
+            }
+            let timer = UPSTREAM_SECONDS
+                .with_label_values(&[&host_of(url)])
+                .start_timer();
+            let result = request.send().await;
+            timer.observe_duration();
+
+            let retryable = match &result {
+                Ok(response) => response.status().is_server_error(),
+                Err(_) => true,
+            };
+            if !retryable || retry >= self.config.retries {
+                return result.map_err(|_| FetchError::Transient);
+            }
+
+            rocket::tokio::time::sleep(self.backoff(retry)).await;
+            retry += 1;
+        }
+    }
+
+    /// Whether an upstream answers a `HEAD` at all, whatever it answers with.
+    /// Tokens and rate limits are left alone, and failures aren't retried.
+    pub async fn reachable(&self, url: &str) -> bool {
+        self.probe(url, async { self.client.head(url).send().await.is_ok() })
+            .await
+    }
+
+    /// Whether an upstream answers a `GET` of a URL that doesn't count against
+    /// its rate limits, such as GitHub's `/rate_limit`, whatever it answers
+    /// with. The request is made as any other, so the limits the upstream
+    /// reports are noted.
+    pub async fn reachable_with_limits(&self, url: &str) -> bool {
+        self.probe(url, async {
+            let fetched = self.get(url, &Validators::default()).await;
+            !matches!(fetched, Err(FetchError::Transient))
+        })
+        .await
+    }
+
+    /// Probes a URL with `check`, unless it was probed recently, in which case
+    /// what was found then is given.
+    async fn probe(&self, url: &str, check: impl Future<Output = bool>) -> bool {
+        let recent = self.probes.lock().unwrap().get(url).copied();
+        if let Some((probed_at, reachable)) = recent {
+            if probed_at.elapsed() < PROBE_TTL {
+                return reachable;
+            }
+        }
+        let reachable = check.await;
+        let mut probes = self.probes.lock().unwrap();
+        probes.insert(url.to_string(), (Instant::now(), reachable));
+        reachable
+    }
+
+    /// Whether requests for a URL are authenticated with a token.
+    pub fn authenticates(&self, url: &str) -> bool {
+        let host = host_of(url);
+        self.tokens
+            .iter()
+            .any(|pool| pool.host() == host && !pool.tokens().is_empty())
+    }
+
+    /// How many more GET requests can be made for a URL before its host limits
+    /// us, across its tokens, or without one if it has none. Tokens whose
+    /// counts aren't known, such as those unused since their limit reset, are
+    /// left out, so this errs low. `None` if no count is known at all.
+    pub fn headroom(&self, url: &str) -> Option<u64> {
+        self.host_headroom(&host_of(url), resource_of(url, None))
+    }
+
+    /// Like `headroom`, but for queries posted to a URL, such as GraphQL
+    /// queries, which may spend from a limit of their own.
+    pub fn query_headroom(&self, url: &str) -> Option<u64> {
+        self.host_headroom(&host_of(url), QUERY_RESOURCE)
+    }
+
+    /// The headroom of each host that has reported its rate limits, for each
+    /// resource reported on.
+    pub fn rate_limits(&self) -> Vec<(String, String, u64)> {
+        self.limits
+            .resources()
+            .into_iter()
+            .filter_map(|(host, resource)| {
+                let headroom = self.host_headroom(&host, &resource)?;
+                Some((host, resource, headroom))
+            })
+            .collect()
+    }
+
+    fn host_headroom(&self, host: &str, resource: &str) -> Option<u64> {
+        let tokens = match self.tokens.iter().find(|pool| pool.host() == host) {
+            Some(pool) => pool.tokens(),
+            None => Vec::new(),
+        };
+        // Requests are only made without a token once there are none left.
+        let known: Vec<u64> = if tokens.is_empty() {
+            self.limits
+                .remaining(host, None, resource)
+                .into_iter()
+                .collect()
+        } else {
+            tokens
+                .iter()
+                .filter_map(|token| self.limits.remaining(host, Some(token), resource))
+                .collect()
+        };
+        (!known.is_empty()).then(|| known.iter().sum())
+    }
+
+    /// Fetches a JSON payload. Requests are made with the first token in turn
+    /// that isn't rate limited, and once the pool is exhausted without one.
+    /// Tokens the upstream rejects are dropped, and tokens it limits are set
+    /// aside until the limit resets, with the request retried either way.
+    ///
+    /// The request is made conditional on the validators given, if any, in
+    /// which case the upstream may answer that the payload is unchanged.
+    pub async fn get(&self, url: &str, validators: &Validators) -> Result<Fetched, FetchError> {
+        self.fetch(url, validators, None).await
+    }
+
+    /// Posts a query, such as a GraphQL query, and fetches the JSON payload
+    /// answering it, in the same way as `get`.
+    pub async fn post(&self, url: &str, query: &Value) -> Result<Value, FetchError> {
+        match self.fetch(url, &Validators::default(), Some(query)).await? {
+            Fetched::Modified(payload, _) => Ok(payload),
+            Fetched::NotModified => Err(FetchError::ServerError),
+        }
+    }
+
+    async fn fetch(
+        &self,
+        url: &str,
+        validators: &Validators,
+        body: Option<&Value>,
+    ) -> Result<Fetched, FetchError> {
+        let host = host_of(url);
+        let fetched = self.fetch_from(&host, url, validators, body).await;
+        if let Err(e) = fetched {
+            log::info!(url, error:? = e; "Failed to fetch {}: {:?}", url, e);
+            UPSTREAM_ERRORS
+                .with_label_values(&[&host, &format!("{:?}", e)])
+                .inc();
+        }
+        fetched
+    }
+
+    async fn fetch_from(
+        &self,
+        host: &str,
+        url: &str,
+        validators: &Validators,
+        body: Option<&Value>,
+    ) -> Result<Fetched, FetchError> {
+        let pool = self.tokens.iter().find(|pool| pool.host() == host);
+        let resource = resource_of(url, body);
+        let limited = |token: Option<&str>| self.limits.is_limited(host, token, resource);
+
+        let (response, limited) = loop {
+            let token = pool.and_then(|pool| pool.next(|token| !limited(Some(token))));
+            if token.is_none() && limited(None) {
+                return Err(FetchError::RateLimited);
+            }
+
+            let response = self.send(url, token.as_deref(), validators, body).await?;
+            let status = response.status().as_u16();
+            log::info!(url, status; "Fetched {} with status {}", url, status);
+            let limited = self
+                .limits
+                .update(host, token.as_deref(), resource, &response);
+
+            match token {
+                Some(token) if response.status() == StatusCode::UNAUTHORIZED => {
+                    pool.unwrap().revoke(&token)
+                }
+                Some(_) if limited && !response.status().is_success() => continue,
+                _ => break (response, limited),
+            }
+        };
+
+        match response.status() {
+            status if status.is_success() => {
+                let validators = Validators::of(response.headers());
+                match response.json().await {
+                    Ok(payload) => Ok(Fetched::Modified(payload, validators)),
+                    Err(e) if e.is_decode() => Err(FetchError::Decode),
+                    Err(_) => Err(FetchError::Transient),
+                }
+            }
+            StatusCode::NOT_MODIFIED => Ok(Fetched::NotModified),
+            _ if limited => Err(FetchError::RateLimited),
+            StatusCode::NOT_FOUND | StatusCode::GONE => Err(FetchError::NotFound),
+            StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => Err(FetchError::Forbidden),
+            StatusCode::TOO_MANY_REQUESTS => Err(FetchError::RateLimited),
+            _ => Err(FetchError::ServerError),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+    use wiremock::matchers::{header, method};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    fn client(tokens: &[&str]) -> UpstreamClient {
+        let tokens = tokens.iter().map(|t| t.to_string()).collect();
+        let pool = TokenPool::new("127.0.0.1", tokens);
+        UpstreamClient::new(ClientConfig::default(), vec![pool]).unwrap()
+    }
+
+    fn modified(payload: u64) -> Fetched {
+        Fetched::Modified(json!(payload), Validators::default())
+    }
+
+    fn reset_in(secs: u64) -> String {
+        let reset = SystemTime::now() + Duration::from_secs(secs);
+        reset
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+            .to_string()
+    }
+
+    #[rocket::async_test]
+    async fn test_drops_rejected_tokens() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(header("Authorization", "Bearer good"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!(1)))
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(401))
+            .mount(&server)
+            .await;
+
+        let client = client(&["bad", "good"]);
+        let url = format!("{}/repos/autophagy/scieldas", server.uri());
+
+        assert_eq!(
+            client.get(&url, &Validators::default()).await,
+            Ok(modified(1))
+        );
+        assert_eq!(
+            client.get(&url, &Validators::default()).await,
+            Ok(modified(1))
+        );
+        assert_eq!(server.received_requests().await.unwrap().len(), 3);
+    }
+
+    #[rocket::async_test]
+    async fn test_backs_off_until_reset() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .set_body_json(json!(1))
+                    .insert_header("X-RateLimit-Remaining", "0")
+                    .insert_header("X-RateLimit-Reset", reset_in(60).as_str()),
+            )
+            .mount(&server)
+            .await;
+
+        let client = client(&[]);
+        let url = format!("{}/repos/autophagy/scieldas", server.uri());
+
+        assert_eq!(
+            client.get(&url, &Validators::default()).await,
+            Ok(modified(1))
+        );
+        assert_eq!(
+            client.get(&url, &Validators::default()).await,
+            Err(FetchError::RateLimited)
+        );
+        assert_eq!(server.received_requests().await.unwrap().len(), 1);
+    }
+
+    #[rocket::async_test]
+    async fn test_retry_after() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(429).insert_header("Retry-After", "60"))
+            .mount(&server)
+            .await;
+
+        let client = client(&[]);
+        let url = format!("{}/repos/autophagy/scieldas", server.uri());
+
+        assert_eq!(
+            client.get(&url, &Validators::default()).await,
+            Err(FetchError::RateLimited)
+        );
+        assert_eq!(
+            client.get(&url, &Validators::default()).await,
+            Err(FetchError::RateLimited)
+        );
+        assert_eq!(server.received_requests().await.unwrap().len(), 1);
+    }
+
+    #[rocket::async_test]
+    async fn test_retry_after_zero() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(429).insert_header("Retry-After", "0"))
+            .mount(&server)
+            .await;
+
+        let client = client(&["a", "b"]);
+        let url = format!("{}/repos/autophagy/scieldas", server.uri());
+
+        // Each token, and then no token, is tried once, rather than the same
+        // token over and over.
+        assert_eq!(
+            client.get(&url, &Validators::default()).await,
+            Err(FetchError::RateLimited)
+        );
+        assert_eq!(
+            client.get(&url, &Validators::default()).await,
+            Err(FetchError::RateLimited)
+        );
+        assert_eq!(server.received_requests().await.unwrap().len(), 3);
+    }
+
+    #[rocket::async_test]
+    async fn test_switches_from_limited_tokens() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(header("Authorization", "Bearer limited"))
+            .respond_with(
+                ResponseTemplate::new(403)
+                    .insert_header("X-RateLimit-Remaining", "0")
+                    .insert_header("X-RateLimit-Reset", reset_in(60).as_str()),
+            )
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!(1)))
+            .mount(&server)
+            .await;
+
+        let client = client(&["limited", "spare"]);
+        let url = format!("{}/repos/autophagy/scieldas", server.uri());
+
+        for _ in 0..3 {
+            assert_eq!(
+                client.get(&url, &Validators::default()).await,
+                Ok(modified(1))
+            );
+        }
+        let requests = server.received_requests().await.unwrap();
+        let limited = requests.iter().filter(|r| {
+            let authorization = r.headers.get(&"Authorization".into()).unwrap();
+            authorization.as_str() == "Bearer limited"
+        });
+        assert_eq!(requests.len(), 4);
+        assert_eq!(limited.count(), 1);
+    }
+
+    #[rocket::async_test]
+    async fn test_headroom() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .set_body_json(json!(1))
+                    .insert_header("X-RateLimit-Remaining", "10")
+                    .insert_header("X-RateLimit-Reset", reset_in(60).as_str()),
+            )
+            .mount(&server)
+            .await;
+
+        let client = client(&["a", "b"]);
+        let url = format!("{}/repos/autophagy/scieldas", server.uri());
+        assert_eq!(client.headroom(&url), None);
+
+        // The count of a token unused since its limit reset isn't known, and
+        // requests aren't made without a token while there are tokens.
+        client.get(&url, &Validators::default()).await.unwrap();
+        assert_eq!(client.headroom(&url), Some(10));
+        client.get(&url, &Validators::default()).await.unwrap();
+        assert_eq!(client.headroom(&url), Some(20));
+        assert_eq!(
+            client.rate_limits(),
+            vec![("127.0.0.1".to_string(), "core".to_string(), 20)]
+        );
+    }
+
+    #[rocket::async_test]
+    async fn test_limits_per_resource() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .respond_with(
+                ResponseTemplate::new(403)
+                    .insert_header("X-RateLimit-Resource", "graphql")
+                    .insert_header("X-RateLimit-Remaining", "0")
+                    .insert_header("X-RateLimit-Reset", reset_in(60).as_str()),
+            )
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .set_body_json(json!(1))
+                    .insert_header("X-RateLimit-Resource", "core")
+                    .insert_header("X-RateLimit-Remaining", "100")
+                    .insert_header("X-RateLimit-Reset", reset_in(60).as_str()),
+            )
+            .mount(&server)
+            .await;
+
+        let client = client(&["token"]);
+        let graphql = format!("{}/graphql", server.uri());
+        let url = format!("{}/repos/autophagy/scieldas", server.uri());
+
+        assert_eq!(
+            client.post(&graphql, &json!({"query": "{}"})).await,
+            Err(FetchError::RateLimited)
+        );
+        // Using up the GraphQL limit leaves the REST API's to be spent.
+        assert_eq!(
+            client.get(&url, &Validators::default()).await,
+            Ok(modified(1))
+        );
+        let requests = server.received_requests().await.unwrap();
+        let last = requests.last().unwrap();
+        let authorization = last.headers.get(&"Authorization".into()).unwrap();
+        assert_eq!(authorization.as_str(), "Bearer token");
+        assert_eq!(client.headroom(&url), Some(100));
+        assert_eq!(
+            client.rate_limits(),
+            vec![
+                ("127.0.0.1".to_string(), "core".to_string(), 100),
+                ("127.0.0.1".to_string(), "graphql".to_string(), 0),
+            ]
+        );
+    }
+
+    #[rocket::async_test]
+    async fn test_retries_server_errors() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(502))
+            .up_to_n_times(2)
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!(1)))
+            .mount(&server)
+            .await;
+
+        let client = client(&[]);
+        let url = format!("{}/repos/autophagy/scieldas", server.uri());
+
+        assert_eq!(
+            client.get(&url, &Validators::default()).await,
+            Ok(modified(1))
+        );
+        assert_eq!(server.received_requests().await.unwrap().len(), 3);
+    }
+
+    #[rocket::async_test]
+    async fn test_times_out() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .set_body_json(json!(1))
+                    .set_delay(Duration::from_secs(5)),
+            )
+            .mount(&server)
+            .await;
+
+        let config = ClientConfig {
+            timeout_ms: 100,
+            retries: 1,
+            backoff_ms: 10,
+            ..ClientConfig::default()
+        };
+        let client = UpstreamClient::new(config, vec![]).unwrap();
+        let url = format!("{}/repos/autophagy/scieldas", server.uri());
+
+        let started = SystemTime::now();
+        assert_eq!(
+            client.get(&url, &Validators::default()).await,
+            Err(FetchError::Transient)
+        );
+        assert!(started.elapsed().unwrap() < Duration::from_secs(2));
+        assert_eq!(server.received_requests().await.unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_backoff() {
+        let client = UpstreamClient::new(ClientConfig::default(), vec![]).unwrap();
+        for retry in 0..4 {
+            let delay = 200 << retry;
+            let backoff = client.backoff(retry);
+            assert!(backoff >= Duration::from_millis(delay / 2));
+            assert!(backoff <= Duration::from_millis(delay));
+        }
+    }
+}
@@ -0,0 +1,342 @@
+use crate::admin::AdminConfig;
+use crate::cache::{CacheConfig, StoreConfig};
+use crate::client::ClientConfig;
+use crate::logging::LoggingConfig;
+use crate::scieldas::{Style, Theme};
+use crate::services::crates::CratesConfig;
+use crate::services::github::GithubConfig;
+use crate::throttle::{Budget, BudgetConfig, ThrottleConfig};
+use crate::warming::WarmingConfig;
+use reqwest::Url;
+use rocket::figment::providers::{Env, Format, Toml};
+use rocket::figment::Figment;
+use serde::Deserialize;
+use std::path::{Path, PathBuf};
+
+/// The configuration file read unless `SCIELDAS_CONFIG` names another.
+const CONFIG_FILE: &str = "Scieldas.toml";
+
+/// The configuration of the server, read from `Scieldas.toml` on top of
+/// Rocket's own configuration, with `SCIELDAS_` prefixed environment variables
+/// overriding both, e.g. `SCIELDAS_CACHE__TTL=60`. Like `Rocket.toml`, the file
+/// is split into profiles, e.g.
+///
+/// ```toml
+/// [default.fonts]
+/// dir = "/usr/share/fonts/scieldas"
+///
+/// [default.scields]
+/// theme = "light"
+/// style = "rounded"
+///
+/// [default.services]
+/// codestyles = false
+/// ```
+///
+/// Every other table is documented by the configuration it holds.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct Config {
+    pub fonts: FontsConfig,
+    pub scields: ScieldsConfig,
+    pub services: ServicesConfig,
+    pub cache: CacheConfig,
+    pub upstream: ClientConfig,
+    pub github: GithubConfig,
+    pub crates: CratesConfig,
+    pub throttle: ThrottleConfig,
+    pub warming: WarmingConfig,
+    pub logging: LoggingConfig,
+    pub admin: AdminConfig,
+}
+
+/// Where fonts are loaded from to render text in PNGs.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct FontsConfig {
+    /// A directory to load fonts from instead of the system's fonts. The
+    /// `FONTS_DIR` environment variable is read for this too.
+    pub dir: Option<PathBuf>,
+}
+
+/// How scields are presented when requests don't say.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct ScieldsConfig {
+    pub theme: Theme,
+    pub style: Style,
+}
+
+impl Default for ScieldsConfig {
+    fn default() -> ScieldsConfig {
+        ScieldsConfig {
+            theme: Theme::Dark,
+            style: Style::Flat,
+        }
+    }
+}
+
+/// Which services' scields are served.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServicesConfig {
+    pub crates: bool,
+    pub github: bool,
+    pub licenses: bool,
+    pub codestyles: bool,
+}
+
+impl Default for ServicesConfig {
+    fn default() -> ServicesConfig {
+        ServicesConfig {
+            crates: true,
+            github: true,
+            licenses: true,
+            codestyles: true,
+        }
+    }
+}
+
+/// Rocket's configuration, with `Scieldas.toml`, or the file named by
+/// `SCIELDAS_CONFIG`, and the environment merged on top. `Scieldas.toml` may
+/// be left out, but a file named by `SCIELDAS_CONFIG` must exist.
+pub fn figment() -> Result<Figment, Vec<String>> {
+    let file = match Env::var("SCIELDAS_CONFIG") {
+        Some(file) if !Path::new(&file).is_file() => {
+            return Err(vec![format!("SCIELDAS_CONFIG: {} isn't a file", file)]);
+        }
+        Some(file) => file,
+        None => CONFIG_FILE.to_string(),
+    };
+    Ok(rocket::Config::figment()
+        .merge(
+            Env::raw()
+                .only(&["FONTS_DIR"])
+                .map(|_| "fonts.dir".into())
+                .global(),
+        )
+        .merge(Toml::file(file).nested())
+        .merge(
+            Env::prefixed("SCIELDAS_")
+                .ignore(&["CONFIG"])
+                .split("__")
+                .global(),
+        ))
+}
+
+impl Config {
+    /// Reads and validates the configuration, returning every problem found
+    /// with it if it's unusable. Keys the server doesn't know of in its own
+    /// tables are refused, as they're likely misspelt.
+    pub fn load(figment: &Figment) -> Result<Config, Vec<String>> {
+        let config: Config = figment.extract().map_err(|errors| {
+            errors
+                .into_iter()
+                .map(|e| e.to_string())
+                .collect::<Vec<_>>()
+        })?;
+        let problems = config.problems();
+        if problems.is_empty() {
+            Ok(config)
+        } else {
+            Err(problems)
+        }
+    }
+
+    fn problems(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        let mut check = |valid: bool, problem: String| {
+            if !valid {
+                problems.push(problem);
+            }
+        };
+
+        if let Some(dir) = &self.fonts.dir {
+            check(
+                dir.is_dir(),
+                format!("fonts.dir: {} isn't a directory", dir.display()),
+            );
+        }
+
+        check(
+            self.cache.size > 0,
+            "cache.size: must be at least 1".to_string(),
+        );
+        if let Some(StoreConfig::Redis { url }) = &self.cache.store {
+            check(
+                ::redis::Client::open(url.as_str()).is_ok(),
+                format!("cache.store.url: {} isn't a Redis URL", url),
+            );
+        }
+
+        check(
+            self.upstream.timeout_ms > 0 && self.upstream.connect_timeout_ms > 0,
+            "upstream: timeouts must be at least 1ms".to_string(),
+        );
+
+        let mut urls = vec![
+            ("github.api_url".to_string(), &self.github.api_url),
+            ("crates.api_url".to_string(), &self.crates.api_url),
+        ];
+        if self.github.graphql {
+            urls.push(("github.graphql_url".to_string(), &self.github.graphql_url));
+        }
+        for (name, host) in &self.github.hosts {
+            urls.push((format!("github.hosts.{}.api_url", name), &host.api_url));
+            if let Some(graphql_url) = host.graphql_url.as_ref().filter(|_| self.github.graphql) {
+                urls.push((format!("github.hosts.{}.graphql_url", name), graphql_url));
+            }
+        }
+        for (name, url) in urls {
+            check(
+                is_http_url(url),
+                format!("{}: {} isn't an HTTP URL", name, url),
+            );
+        }
+
+        let mut tokens = self
+            .github
+            .tokens
+            .iter()
+            .chain(self.github.hosts.values().flat_map(|host| &host.tokens));
+        check(
+            tokens.all(|token| !token.trim().is_empty()),
+            "github.tokens: tokens can't be empty".to_string(),
+        );
+
+        if self.throttle.enabled {
+            for (name, budgets) in [
+                ("requests", &self.throttle.requests),
+                ("misses", &self.throttle.misses),
+            ] {
+                for problem in budget_problems(budgets) {
+                    check(false, format!("throttle.{}.{}", name, problem));
+                }
+            }
+        }
+
+        if self.warming.enabled {
+            check(
+                self.warming.interval > 0,
+                "warming.interval: must be at least 1 second".to_string(),
+            );
+        }
+
+        if let Some(token) = &self.admin.token {
+            check(
+                !token.trim().is_empty(),
+                "admin.token: can't be empty, leave it out to disable the admin API".to_string(),
+            );
+        }
+
+        problems
+    }
+}
+
+fn is_http_url(url: &str) -> bool {
+    match Url::parse(url) {
+        Ok(url) => matches!(url.scheme(), "http" | "https") && url.host_str().is_some(),
+        Err(_) => false,
+    }
+}
+
+fn budget_problems(budgets: &BudgetConfig) -> Vec<String> {
+    let check = |name, budget: Budget| {
+        let mut problems = Vec::new();
+        if !(budget.rate.is_finite() && budget.rate >= 0.0) {
+            problems.push(format!("{}.rate: must be a number of at least 0", name));
+        }
+        if !(budget.burst.is_finite() && budget.burst >= 1.0) {
+            problems.push(format!("{}.burst: must be a number of at least 1", name));
+        }
+        problems
+    };
+    let mut problems = check("per_client", budgets.per_client);
+    problems.extend(check("global", budgets.global));
+    problems
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn load(toml: &str) -> Result<Config, Vec<String>> {
+        Config::load(&Figment::from(Toml::string(toml).nested()))
+    }
+
+    #[test]
+    fn test_load() {
+        assert_eq!(load(""), Ok(Config::default()));
+
+        let config = load(
+            r#"
+            [default.scields]
+            theme = "light"
+
+            [default.services]
+            codestyles = false
+
+            [default.cache]
+            ttl = 60
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.scields.theme, Theme::Light);
+        assert!(!config.services.codestyles);
+        assert!(config.services.github);
+        assert_eq!(config.cache.ttl, 60);
+    }
+
+    #[test]
+    fn test_invalid() {
+        let errors = load(
+            r#"
+            [default.cache]
+            ttl = "soon"
+            "#,
+        )
+        .unwrap_err();
+        assert_eq!(errors.len(), 1);
+        assert!(errors[0].contains("cache.ttl"), "{}", errors[0]);
+
+        // Misspelt keys are refused rather than ignored.
+        for (toml, key) in [
+            ("[default.cache]\ntll = 60", "`tll`"),
+            (
+                "[default.cache.store]\nkind = \"disk\"\npath = \"cache\"\nurl = \"redis://\"",
+                "`url`",
+            ),
+        ] {
+            let errors = load(toml).unwrap_err();
+            assert_eq!(errors.len(), 1);
+            assert!(errors[0].contains("unknown field"), "{}", errors[0]);
+            assert!(errors[0].contains(key), "{}", errors[0]);
+        }
+
+        let errors = load(
+            r#"
+            [default.fonts]
+            dir = "/nonexistent/fonts"
+
+            [default.github]
+            api_url = "api.github.com"
+            tokens = [""]
+
+            [default.throttle.misses]
+            per_client = { rate = -1, burst = 0 }
+            global = { rate = 10, burst = 100 }
+            "#,
+        )
+        .unwrap_err();
+        assert_eq!(
+            errors,
+            vec![
+                "fonts.dir: /nonexistent/fonts isn't a directory",
+                "github.api_url: api.github.com isn't an HTTP URL",
+                "github.tokens: tokens can't be empty",
+                "throttle.misses.per_client.rate: must be a number of at least 0",
+                "throttle.misses.per_client.burst: must be a number of at least 1",
+            ]
+        );
+    }
+}
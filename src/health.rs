@@ -0,0 +1,218 @@
+use crate::cache::PayloadCache;
+use crate::client::UpstreamClient;
+use crate::services::crates::CratesConfig;
+use crate::services::github::GithubConfig;
+use rocket::http::{ContentType, Status};
+use rocket::tokio::time;
+use rocket::State;
+use serde_json::json;
+use std::time::Duration;
+
+/// How long each of the readiness checks may take, so that a probe is
+/// answered well within its own timeout however unresponsive whatever it
+/// checks is.
+const CHECK_TIMEOUT: Duration = Duration::from_secs(2);
+
+pub fn routes() -> Vec<rocket::Route> {
+    routes![health, live, ready]
+}
+
+#[get("/")]
+fn health() -> &'static str {
+    "OK"
+}
+
+/// Whether the server is up at all, for liveness probes.
+#[get("/live")]
+fn live() -> &'static str {
+    "OK"
+}
+
+/// Whether the server can answer requests, for readiness probes, with what
+/// that was judged on. Scields can't be rendered without fonts, and payloads
+/// can't be cached without the cache's store, so both must be usable.
+///
+/// Upstreams are reported, along with how much of their rate limits are known
+/// to be left, but not judged on, as cached payloads are served while they're
+/// unreachable, and every instance would be unready at once if they weren't.
+/// GitHub hosts are asked for their rate limits, which doesn't count against
+/// them, rather than sent a `HEAD`, which would. Whether each upstream is
+/// reachable is remembered between probes for a while.
+#[get("/ready")]
+async fn ready(
+    opt: &State<usvg::Options>,
+    cache: &State<PayloadCache>,
+    client: &State<UpstreamClient>,
+    github: &State<GithubConfig>,
+    crates: &State<CratesConfig>,
+) -> (Status, (ContentType, String)) {
+    let faces = opt.fontdb.len();
+    let store = time::timeout(CHECK_TIMEOUT, cache.check())
+        .await
+        .unwrap_or_else(|_| Err("timed out".to_string()));
+
+    let mut upstreams = vec![
+        ("github".to_string(), &github.api_url),
+        ("crates".to_string(), &crates.api_url),
+    ];
+    let mut hosts: Vec<_> = github.hosts.iter().collect();
+    hosts.sort_unstable_by_key(|(name, _)| *name);
+    upstreams.extend(
+        hosts
+            .into_iter()
+            .map(|(name, host)| (format!("github.{}", name), &host.api_url)),
+    );
+    let reachable = upstreams.iter().map(|(name, url)| async move {
+        let check = async {
+            if name.starts_with("github") {
+                let rate_limit = format!("{}/rate_limit", url);
+                client.reachable_with_limits(&rate_limit).await
+            } else {
+                client.reachable(url).await
+            }
+        };
+        time::timeout(CHECK_TIMEOUT, check).await.unwrap_or(false)
+    });
+    let reachable = futures::future::join_all(reachable).await;
+
+    let ready = faces > 0 && store.is_ok();
+    let body = json!({
+        "ready": ready,
+        "fonts": {
+            "ready": faces > 0,
+            "faces": faces,
+        },
+        "cache": {
+            "ready": store.is_ok(),
+            "backend": cache.backend(),
+            "error": store.err(),
+        },
+        "upstreams": upstreams
+            .iter()
+            .zip(reachable)
+            .map(|((name, url), reachable)| json!({
+                "name": name,
+                "url": url,
+                "reachable": reachable,
+                "rate_limit_remaining": client.headroom(url),
+            }))
+            .collect::<Vec<_>>(),
+    });
+
+    let status = if ready {
+        Status::Ok
+    } else {
+        Status::ServiceUnavailable
+    };
+    (status, (ContentType::JSON, body.to_string()))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cache::CacheConfig;
+    use crate::client::ClientConfig;
+    use crate::services::github::GithubHost;
+    use rocket::local::asynchronous::Client;
+    use serde_json::Value;
+    use std::collections::HashMap;
+    use std::time::{Instant, SystemTime, UNIX_EPOCH};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    #[rocket::async_test]
+    async fn test_health() {
+        let server = MockServer::start().await;
+        let reset = SystemTime::now() + Duration::from_secs(3600);
+        let reset = reset.duration_since(UNIX_EPOCH).unwrap().as_secs();
+        Mock::given(wiremock::matchers::path("/rate_limit"))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .set_body_json(json!({}))
+                    .insert_header("X-RateLimit-Remaining", "59")
+                    .insert_header("X-RateLimit-Reset", reset.to_string().as_str())
+                    .insert_header("X-RateLimit-Resource", "core"),
+            )
+            .mount(&server)
+            .await;
+
+        let slow = MockServer::start().await;
+        Mock::given(wiremock::matchers::any())
+            .respond_with(ResponseTemplate::new(200).set_delay(Duration::from_secs(30)))
+            .mount(&slow)
+            .await;
+        // Rate limits are kept by host, so the other upstreams are given
+        // other names for the same address to keep them apart.
+        let slow_url = slow.uri().replace("127.0.0.1", "localhost");
+
+        let github = GithubConfig {
+            api_url: server.uri(),
+            hosts: HashMap::from([(
+                "slow".to_string(),
+                GithubHost {
+                    api_url: slow_url.clone(),
+                    graphql_url: None,
+                    tokens: Vec::new(),
+                },
+            )]),
+            ..GithubConfig::default()
+        };
+        // Nothing listens on port 1.
+        let crates = CratesConfig {
+            api_url: "http://localhost:1/api/v1/crates".to_string(),
+        };
+        let rocket = rocket::build()
+            .manage(usvg::Options::default())
+            .manage(PayloadCache::new(CacheConfig::default()))
+            .manage(UpstreamClient::new(ClientConfig::default(), vec![]).unwrap())
+            .manage(github)
+            .manage(crates)
+            .mount("/health", routes());
+        let client = Client::tracked(rocket).await.unwrap();
+
+        for uri in ["/health", "/health/live"] {
+            let response = client.get(uri).dispatch().await;
+            assert_eq!(response.status(), Status::Ok);
+            assert_eq!(response.into_string().await.unwrap(), "OK");
+        }
+
+        // Without any fonts, scields can't be rendered. An upstream that
+        // doesn't answer doesn't hold up the probe.
+        let started = Instant::now();
+        let response = client.get("/health/ready").dispatch().await;
+        assert!(started.elapsed() < CHECK_TIMEOUT * 2);
+        assert_eq!(response.status(), Status::ServiceUnavailable);
+        let body: Value = serde_json::from_str(&response.into_string().await.unwrap()).unwrap();
+        assert_eq!(
+            body,
+            json!({
+                "ready": false,
+                "fonts": {"ready": false, "faces": 0},
+                "cache": {"ready": true, "backend": "memory", "error": null},
+                "upstreams": [
+                    {
+                        "name": "github",
+                        "url": server.uri(),
+                        "reachable": true,
+                        "rate_limit_remaining": 59,
+                    },
+                    {
+                        "name": "crates",
+                        "url": "http://localhost:1/api/v1/crates",
+                        "reachable": false,
+                        "rate_limit_remaining": null,
+                    },
+                    {
+                        "name": "github.slow",
+                        "url": slow_url,
+                        "reachable": false,
+                        "rate_limit_remaining": null,
+                    },
+                ],
+            })
+        );
+
+        // Upstreams aren't probed again so soon.
+        client.get("/health/ready").dispatch().await;
+        assert_eq!(server.received_requests().await.unwrap().len(), 1);
+    }
+}
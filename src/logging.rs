@@ -0,0 +1,288 @@
+use log::kv::{self, VisitSource};
+use log::{LevelFilter, Log, Metadata, Record, SetLoggerError};
+use rand::Rng;
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::http::Header;
+use rocket::{Data, Request, Response};
+use serde::Deserialize;
+use serde_json::{Map, Value};
+use std::future::Future;
+use std::io::Write;
+use std::time::SystemTime;
+
+/// The header carrying the ID of a request, taken from the client if given.
+const REQUEST_ID_HEADER: &str = "X-Request-Id";
+
+/// Configuration for logging, read from the `logging` table of the
+/// configuration, e.g.
+///
+/// ```toml
+/// [default.logging]
+/// level = "info"
+/// format = "json"
+/// ```
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct LoggingConfig {
+    pub level: LevelFilter,
+    pub format: LogFormat,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// A JSON object per line.
+    Json,
+    /// A line of plain text, with any fields appended as `key=value`.
+    Text,
+}
+
+impl Default for LoggingConfig {
+    fn default() -> LoggingConfig {
+        LoggingConfig {
+            level: LevelFilter::Info,
+            format: LogFormat::Json,
+        }
+    }
+}
+
+rocket::tokio::task_local! {
+    static REQUEST_ID: Option<RequestId>;
+}
+
+/// The ID of a request, logged with everything done to answer it, including
+/// the upstream fetches it starts.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RequestId(pub String);
+
+impl RequestId {
+    /// The ID of a request, made up if it doesn't have one yet.
+    pub fn of<'r>(request: &'r Request<'_>) -> &'r RequestId {
+        request.local_cache(|| {
+            let id = request
+                .headers()
+                .get_one(REQUEST_ID_HEADER)
+                .filter(|id| is_valid(id));
+            match id {
+                Some(id) => RequestId(id.to_string()),
+                None => RequestId(format!("{:016x}", rand::thread_rng().gen::<u64>())),
+            }
+        })
+    }
+
+    /// The ID of the request the current task is working on, if any.
+    pub fn current() -> Option<RequestId> {
+        REQUEST_ID.try_with(Clone::clone).ok().flatten()
+    }
+
+    /// Runs a future as working on the request with the given ID, if any.
+    pub async fn scope<F: Future>(id: Option<RequestId>, f: F) -> F::Output {
+        REQUEST_ID.scope(id, f).await
+    }
+}
+
+/// Whether an ID given by a client is short and plain enough to log.
+fn is_valid(id: &str) -> bool {
+    !id.is_empty()
+        && id.len() <= 64
+        && id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+/// Gives each request an ID, returned in the `X-Request-Id` header, and logs
+/// each response along with it.
+pub struct RequestLogger;
+
+#[rocket::async_trait]
+impl Fairing for RequestLogger {
+    fn info(&self) -> Info {
+        Info {
+            name: "Request Logger",
+            kind: Kind::Request | Kind::Response,
+        }
+    }
+
+    async fn on_request(&self, request: &mut Request<'_>, _: &mut Data<'_>) {
+        RequestId::of(request);
+    }
+
+    async fn on_response<'r>(&self, request: &'r Request<'_>, response: &mut Response<'r>) {
+        let id = RequestId::of(request);
+        let status = response.status().code;
+        log::info!(
+            request_id = id.0.as_str(),
+            method = request.method().as_str(),
+            uri:% = request.uri(),
+            status;
+            "{} {} answered with {}",
+            request.method(),
+            request.uri(),
+            response.status()
+        );
+        response.set_header(Header::new(REQUEST_ID_HEADER, id.0.clone()));
+    }
+}
+
+/// Writes log records to stderr, one per line, with the ID of the request
+/// they were logged for.
+struct Logger {
+    config: LoggingConfig,
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata<'_>) -> bool {
+        // As with Rocket's own logger, the chatter of the HTTP stack is only
+        // logged when debugging.
+        let target = metadata.target();
+        let chatter = ["hyper", "h2", "rustls", "reqwest"]
+            .iter()
+            .any(|prefix| target.starts_with(prefix));
+        metadata.level() <= self.config.level
+            && (!chatter || self.config.level >= LevelFilter::Debug)
+    }
+
+    fn log(&self, record: &Record<'_>) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let line = match self.config.format {
+            LogFormat::Json => json_line(record),
+            LogFormat::Text => text_line(record),
+        };
+        let _ = writeln!(std::io::stderr().lock(), "{}", line);
+    }
+
+    fn flush(&self) {}
+}
+
+/// Installs the logger. This must happen before Rocket is built, as Rocket
+/// otherwise installs its own.
+pub fn init(config: LoggingConfig) -> Result<(), SetLoggerError> {
+    log::set_max_level(config.level);
+    log::set_boxed_logger(Box::new(Logger { config }))
+}
+
+struct Fields<'a>(&'a mut Map<String, Value>);
+
+impl<'kvs> VisitSource<'kvs> for Fields<'_> {
+    fn visit_pair(&mut self, key: kv::Key<'kvs>, value: kv::Value<'kvs>) -> Result<(), kv::Error> {
+        let value = serde_json::to_value(&value).unwrap_or_else(|_| value.to_string().into());
+        self.0.insert(key.to_string(), value);
+        Ok(())
+    }
+}
+
+/// The fields of a record, with the ID of the current request unless the
+/// record gives one itself.
+fn fields(record: &Record<'_>) -> Map<String, Value> {
+    let mut fields = Map::new();
+    if let Some(id) = RequestId::current() {
+        fields.insert("request_id".to_string(), id.0.into());
+    }
+    let _ = record.key_values().visit(&mut Fields(&mut fields));
+    fields
+}
+
+fn timestamp() -> String {
+    humantime::format_rfc3339_millis(SystemTime::now()).to_string()
+}
+
+/// Rocket marks records to be indented under the previous one by suffixing
+/// their targets, which means nothing here.
+fn target<'a>(record: &Record<'a>) -> &'a str {
+    let target = record.target();
+    target.strip_suffix("::_").unwrap_or(target)
+}
+
+fn json_line(record: &Record<'_>) -> String {
+    let mut line = Map::new();
+    line.insert("timestamp".to_string(), timestamp().into());
+    line.insert("level".to_string(), record.level().as_str().into());
+    line.insert("target".to_string(), target(record).into());
+    line.insert("message".to_string(), record.args().to_string().into());
+    line.extend(fields(record));
+    Value::Object(line).to_string()
+}
+
+fn text_line(record: &Record<'_>) -> String {
+    let mut line = format!(
+        "{} {:<5} {}: {}",
+        timestamp(),
+        record.level(),
+        target(record),
+        record.args()
+    );
+    for (key, value) in fields(record) {
+        let value = match value {
+            Value::String(value) => value,
+            value => value.to_string(),
+        };
+        line.push_str(&format!(" {}={}", key, value));
+    }
+    line
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rocket::local::asynchronous::Client;
+
+    #[rocket::async_test]
+    async fn test_request_ids() {
+        let rocket = rocket::build()
+            .attach(RequestLogger)
+            .mount("/", routes![crate::index]);
+        let client = Client::tracked(rocket).await.unwrap();
+
+        let response = client.get("/").dispatch().await;
+        let id = response.headers().get_one(REQUEST_ID_HEADER).unwrap();
+        assert_eq!(id.len(), 16);
+
+        let response = client
+            .get("/")
+            .header(Header::new(REQUEST_ID_HEADER, "abc-123"))
+            .dispatch()
+            .await;
+        assert_eq!(
+            response.headers().get_one(REQUEST_ID_HEADER),
+            Some("abc-123")
+        );
+
+        // IDs that can't be logged as they are are replaced.
+        let response = client
+            .get("/")
+            .header(Header::new(REQUEST_ID_HEADER, "abc 123"))
+            .dispatch()
+            .await;
+        assert_ne!(
+            response.headers().get_one(REQUEST_ID_HEADER),
+            Some("abc 123")
+        );
+    }
+
+    #[rocket::async_test]
+    async fn test_json_line() {
+        let fields = [("url", "https://api.github.com/users/autophagy")];
+        let line = |id| {
+            let record = Record::builder()
+                .args(format_args!("Fetched"))
+                .level(log::Level::Info)
+                .target("scieldas::client::_")
+                .key_values(&fields)
+                .build();
+            let line = REQUEST_ID.sync_scope(id, || json_line(&record));
+            serde_json::from_str::<Value>(&line).unwrap()
+        };
+
+        let logged = line(Some(RequestId("abc".to_string())));
+        assert_eq!(logged["level"], "INFO");
+        assert_eq!(logged["target"], "scieldas::client");
+        assert_eq!(logged["message"], "Fetched");
+        assert_eq!(logged["url"], "https://api.github.com/users/autophagy");
+        assert_eq!(logged["request_id"], "abc");
+
+        let logged = line(None);
+        assert!(logged.get("request_id").is_none());
+    }
+}
@@ -1,39 +1,131 @@
 #[macro_use]
 extern crate rocket;
 
+mod admin;
+mod cache;
+mod client;
+mod config;
+mod health;
+mod logging;
+mod metrics;
 mod scieldas;
 mod services;
+mod throttle;
+mod tokens;
 mod utils;
+mod warming;
 
-use reqwest::Client;
-use std::env;
+use cache::{DiskStore, PayloadCache, RedisStore, StoreConfig};
+use client::UpstreamClient;
+use config::Config;
+use logging::RequestLogger;
+use metrics::RequestMetrics;
+use rocket::Request;
+use scieldas::{ScieldError, ScieldRequestError};
+use std::sync::Arc;
+use throttle::Throttle;
+use utils::{FetchError, InFlight};
+use warming::Warmer;
 
 #[get("/")]
 fn index() -> &'static str {
     "Scieldas."
 }
 
-#[get("/health")]
-fn health() -> &'static str {
-    "OK"
+#[catch(404)]
+fn not_found(_: &Request) -> ScieldError {
+    ScieldError::NotFound
+}
+
+#[catch(422)]
+fn unprocessable_entity(_: &Request) -> ScieldError {
+    ScieldError::Request(ScieldRequestError::InvalidBody)
+}
+
+#[catch(429)]
+fn too_many_requests(_: &Request) -> ScieldError {
+    ScieldError::Upstream(FetchError::Throttled)
 }
 
 #[launch]
-fn rocket() -> _ {
-    let client = Client::builder().user_agent("scieldas").build().unwrap();
+async fn rocket() -> _ {
+    let loaded = config::figment().and_then(|figment| Ok((Config::load(&figment)?, figment)));
+    let (config, figment) = match loaded {
+        Ok(loaded) => loaded,
+        Err(problems) => {
+            eprintln!("Invalid configuration:");
+            for problem in problems {
+                eprintln!("  {}", problem);
+            }
+            std::process::exit(1);
+        }
+    };
+
+    logging::init(config.logging).expect("failed to install logger");
+
     let mut opt = usvg::Options::default();
+    match &config.fonts.dir {
+        Some(dir) => opt.fontdb.load_fonts_dir(dir),
+        None => opt.fontdb.load_system_fonts(),
+    };
 
-    match env::var("FONTS_DIR") {
-        Ok(dir) => opt.fontdb.load_fonts_dir(dir),
-        Err(_) => opt.fontdb.load_system_fonts(),
+    let store = config.cache.store.clone();
+    let cache = PayloadCache::new(config.cache);
+    let cache = match store {
+        Some(StoreConfig::Disk { path }) => {
+            let store =
+                DiskStore::open(&path, cache.retention()).expect("failed to open disk cache");
+            cache.with_store(Arc::new(store))
+        }
+        Some(StoreConfig::Redis { url }) => {
+            let store = RedisStore::connect(&url, cache.retention())
+                .await
+                .expect("invalid Redis URL");
+            cache.with_store(Arc::new(store))
+        }
+        None => cache,
     };
 
-    rocket::build()
+    let throttle = Throttle::new(config.throttle);
+    let warmer = Warmer::new(config.warming);
+    let client = UpstreamClient::new(config.upstream, config.github.token_pools())
+        .expect("failed to build upstream client");
+
+    let mut rocket = rocket::custom(figment)
         .manage(client)
+        .manage(cache)
+        .manage(config.github)
+        .manage(config.crates)
+        .manage(config.admin)
+        .manage(config.scields)
+        .manage(InFlight::default())
+        .manage(throttle)
+        .manage(warmer.clone())
         .manage(opt)
-        .mount("/", routes![index, health])
-        .mount("/crates", services::crates::routes())
-        .mount("/github", services::github::routes())
-        .mount("/licenses", services::licenses::routes())
-        .mount("/codestyles", services::codestyles::routes())
+        .attach(warmer)
+        .attach(RequestMetrics)
+        .attach(RequestLogger)
+        .register(
+            "/",
+            catchers![not_found, unprocessable_entity, too_many_requests],
+        )
+        .register("/admin", admin::catchers())
+        .mount("/", routes![index, metrics::metrics])
+        .mount("/admin", admin::routes())
+        .mount("/health", health::routes());
+
+    let services = config.services;
+    if services.crates {
+        rocket = rocket.mount("/crates", services::crates::routes());
+    }
+    if services.github {
+        rocket = rocket.mount("/github", services::github::routes());
+    }
+    if services.licenses {
+        rocket = rocket.mount("/licenses", services::licenses::routes());
+    }
+    if services.codestyles {
+        rocket = rocket.mount("/codestyles", services::codestyles::routes());
+    }
+    rocket
 }
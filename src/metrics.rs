@@ -0,0 +1,196 @@
+use crate::client::UpstreamClient;
+use prometheus::{
+    register_histogram_vec, register_int_counter, register_int_counter_vec, register_int_gauge_vec,
+    Encoder, HistogramVec, IntCounter, IntCounterVec, IntGaugeVec, TextEncoder,
+};
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::http::ContentType;
+use rocket::{Request, Response, State};
+use std::sync::LazyLock;
+
+pub static REQUESTS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "scieldas_requests_total",
+        "Requests answered, by route, filetype and status.",
+        &["route", "filetype", "status"]
+    )
+    .expect("failed to register metric")
+});
+
+pub static RENDER_SECONDS: LazyLock<HistogramVec> = LazyLock::new(|| {
+    register_histogram_vec!(
+        "scieldas_render_seconds",
+        "Time taken to render scields, by filetype.",
+        &["filetype"]
+    )
+    .expect("failed to register metric")
+});
+
+pub static CACHE_LOOKUPS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "scieldas_cache_lookups_total",
+        "Lookups of upstream payloads in the cache, by whether they were fresh, stale or missing.",
+        &["outcome"]
+    )
+    .expect("failed to register metric")
+});
+
+pub static CACHE_EVICTIONS: LazyLock<IntCounter> = LazyLock::new(|| {
+    register_int_counter!(
+        "scieldas_cache_evictions_total",
+        "Payloads evicted from the in-memory cache to make room for others."
+    )
+    .expect("failed to register metric")
+});
+
+pub static UPSTREAM_SECONDS: LazyLock<HistogramVec> = LazyLock::new(|| {
+    register_histogram_vec!(
+        "scieldas_upstream_request_seconds",
+        "Time taken by upstream requests, including failed ones, by host.",
+        &["host"]
+    )
+    .expect("failed to register metric")
+});
+
+pub static UPSTREAM_ERRORS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "scieldas_upstream_errors_total",
+        "Failed upstream fetches, by host and why they failed.",
+        &["host", "error"]
+    )
+    .expect("failed to register metric")
+});
+
+pub static RATE_LIMIT_REMAINING: LazyLock<IntGaugeVec> = LazyLock::new(|| {
+    register_int_gauge_vec!(
+        "scieldas_rate_limit_remaining",
+        "Requests left before an upstream host limits us, across its tokens, by resource.",
+        &["host", "resource"]
+    )
+    .expect("failed to register metric")
+});
+
+/// Counts the requests answered. Routes are labelled by their URI, rather
+/// than the URI requested, so that each is counted as one series.
+pub struct RequestMetrics;
+
+#[rocket::async_trait]
+impl Fairing for RequestMetrics {
+    fn info(&self) -> Info {
+        Info {
+            name: "Request Metrics",
+            kind: Kind::Response,
+        }
+    }
+
+    async fn on_response<'r>(&self, request: &'r Request<'_>, response: &mut Response<'r>) {
+        let route = request
+            .route()
+            .map_or("unmatched".to_string(), |route| route.uri.to_string());
+        let filetype = response
+            .content_type()
+            .and_then(|content_type| content_type.extension().map(ToString::to_string))
+            .unwrap_or_default();
+        REQUESTS
+            .with_label_values(&[&route, &filetype, &response.status().code.to_string()])
+            .inc();
+    }
+}
+
+/// All metrics, in Prometheus' text format.
+#[get("/metrics")]
+pub fn metrics(client: &State<UpstreamClient>) -> (ContentType, String) {
+    RATE_LIMIT_REMAINING.reset();
+    for (host, resource, remaining) in client.rate_limits() {
+        let remaining = i64::try_from(remaining).unwrap_or(i64::MAX);
+        RATE_LIMIT_REMAINING
+            .with_label_values(&[&host, &resource])
+            .set(remaining);
+    }
+
+    let mut buffer = Vec::new();
+    TextEncoder::new()
+        .encode(&prometheus::gather(), &mut buffer)
+        .expect("failed to encode metrics");
+    (
+        ContentType::new("text", "plain").with_params(("version", "0.0.4")),
+        String::from_utf8(buffer).expect("metrics aren't UTF-8"),
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::client::{ClientConfig, Validators};
+    use crate::services::licenses;
+    use crate::tokens::TokenPool;
+    use rocket::local::asynchronous::Client;
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    #[rocket::async_test]
+    async fn test_metrics() {
+        let rocket = rocket::build()
+            .manage(UpstreamClient::new(ClientConfig::default(), vec![]).unwrap())
+            .manage(usvg::Options::default())
+            .attach(RequestMetrics)
+            .mount("/", routes![metrics])
+            .mount("/licenses", licenses::routes());
+        let client = Client::tracked(rocket).await.unwrap();
+
+        client.get("/licenses/mit.svg").dispatch().await;
+        client.get("/licenses/mit.png").dispatch().await;
+        client.get("/nowhere").dispatch().await;
+
+        let response = client.get("/metrics").dispatch().await;
+        let content_type = response.content_type().unwrap();
+        assert_eq!(content_type.to_string(), "text/plain; version=0.0.4");
+        let body = response.into_string().await.unwrap();
+
+        for line in [
+            r#"scieldas_requests_total{filetype="svg",route="/licenses/<license>",status="200"}"#,
+            r#"scieldas_requests_total{filetype="png",route="/licenses/<license>",status="200"}"#,
+            r#"route="unmatched",status="404"}"#,
+            r#"scieldas_render_seconds_count{filetype="png"}"#,
+        ] {
+            assert!(body.contains(line), "{} missing from {}", line, body);
+        }
+    }
+
+    #[rocket::async_test]
+    async fn test_rate_limit_remaining() {
+        let server = MockServer::start().await;
+        let reset = SystemTime::now() + Duration::from_secs(3600);
+        let reset = reset.duration_since(UNIX_EPOCH).unwrap().as_secs();
+        Mock::given(wiremock::matchers::any())
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .set_body_json(1)
+                    .insert_header("X-RateLimit-Remaining", "42")
+                    .insert_header("X-RateLimit-Reset", reset.to_string().as_str()),
+            )
+            .mount(&server)
+            .await;
+
+        let pool = TokenPool::new("127.0.0.1", vec!["token".to_string()]);
+        let upstream = UpstreamClient::new(ClientConfig::default(), vec![pool]).unwrap();
+        upstream
+            .get(&server.uri(), &Validators::default())
+            .await
+            .unwrap();
+        let rocket = rocket::build()
+            .manage(upstream)
+            .mount("/", routes![metrics]);
+        let client = Client::tracked(rocket).await.unwrap();
+
+        let body = client
+            .get("/metrics")
+            .dispatch()
+            .await
+            .into_string()
+            .await
+            .unwrap();
+        let line = r#"scieldas_rate_limit_remaining{host="127.0.0.1",resource="core"} 42"#;
+        assert!(body.contains(line), "{} missing from {}", line, body);
+    }
+}
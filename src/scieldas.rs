@@ -1,186 +1,789 @@
 use std::io::Cursor;
 
-use rocket::http::ContentType;
-use rocket::request::{FromParam, Request};
+use rocket::form::FromForm;
+use rocket::http::uri::{fmt::Path, Segments};
+use rocket::http::{Accept, ContentType, Header, MediaType, Status};
+use rocket::request::{self, FromParam, FromRequest, FromSegments, Request};
 use rocket::response::{self, Responder, Response};
+use serde::Deserialize;
 
 use std::cmp;
-use std::path::PathBuf;
+use std::collections::hash_map::DefaultHasher;
+use std::convert::Infallible;
+use std::fmt;
+use std::hash::{Hash, Hasher};
 use std::str::FromStr;
+use std::time::Duration;
+
+use crate::config::ScieldsConfig;
+use crate::logging::RequestId;
+use crate::metrics::RENDER_SECONDS;
+use crate::utils::{FetchError, Freshness};
 
 /// Scield Request
 /// ==============
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum SupportedFiletype {
     Png,
     Svg,
     Txt,
+    Json,
+}
+
+impl SupportedFiletype {
+    const EXTENSIONS: &'static [&'static str] = &["png", "svg", "txt", "json"];
+
+    /// Extensions of formats scields are commonly asked for in but that we
+    /// don't render. Values ending in these are refused outright, as no crate,
+    /// repository or branch is likely to be named so.
+    const UNSUPPORTED_EXTENSIONS: &'static [&'static str] = &[
+        "gif", "jpg", "jpeg", "webp", "bmp", "ico", "tif", "tiff", "avif", "pdf",
+    ];
+
+    fn from_extension(extension: &str) -> Option<SupportedFiletype> {
+        match extension {
+            "png" => Some(SupportedFiletype::Png),
+            "svg" => Some(SupportedFiletype::Svg),
+            "txt" => Some(SupportedFiletype::Txt),
+            "json" => Some(SupportedFiletype::Json),
+            _ => None,
+        }
+    }
+
+    fn extension(&self) -> &'static str {
+        match self {
+            SupportedFiletype::Png => "png",
+            SupportedFiletype::Svg => "svg",
+            SupportedFiletype::Txt => "txt",
+            SupportedFiletype::Json => "json",
+        }
+    }
+
+    /// Every filetype, in order of preference when a request doesn't mind.
+    const ALL: [SupportedFiletype; 4] = [
+        SupportedFiletype::Svg,
+        SupportedFiletype::Png,
+        SupportedFiletype::Txt,
+        SupportedFiletype::Json,
+    ];
+
+    /// The filetypes a media type range covers, and how specific the range
+    /// is, from `*/*` up to a full media type.
+    fn matching(media_type: &MediaType) -> (&'static [SupportedFiletype], u8) {
+        use SupportedFiletype::*;
+        match (media_type.top().as_str(), media_type.sub().as_str()) {
+            ("*", "*") => (&[Svg, Png, Txt, Json], 0),
+            ("image", "*") => (&[Svg, Png], 1),
+            ("text", "*") => (&[Txt], 1),
+            _ if media_type.is_svg() => (&[Svg], 2),
+            _ if media_type.is_png() => (&[Png], 2),
+            _ if media_type.is_plain() => (&[Txt], 2),
+            _ if media_type.is_json() => (&[Json], 2),
+            _ => (&[], 2),
+        }
+    }
+
+    /// Picks the filetype for a request that didn't specify an extension,
+    /// honouring the weights in the `Accept` header and falling back to SVG
+    /// when nothing acceptable is offered. As RFC 9110 has it, each filetype
+    /// is weighed by the most specific range covering it, so `image/png`
+    /// is acceptable even alongside `image/*;q=0`, and filetypes weighed
+    /// `q=0` are refused. Ties go to the range given first.
+    pub fn negotiate(accept: Option<&Accept>) -> SupportedFiletype {
+        let media_types: Vec<_> = accept.iter().flat_map(|a| a.iter()).collect();
+
+        SupportedFiletype::ALL
+            .into_iter()
+            .filter_map(|filetype| {
+                let (position, weight) = media_types
+                    .iter()
+                    .enumerate()
+                    .filter_map(|(position, m)| {
+                        let (filetypes, specificity) = SupportedFiletype::matching(m);
+                        filetypes.contains(&filetype).then_some((
+                            specificity,
+                            cmp::Reverse(position),
+                            m.weight_or(1.0),
+                        ))
+                    })
+                    .max_by_key(|(specificity, position, _)| (*specificity, *position))
+                    .map(|(_, cmp::Reverse(position), weight)| (position, weight))?;
+                (weight > 0.0).then_some((filetype, weight, position))
+            })
+            .min_by(|a, b| b.1.total_cmp(&a.1).then(a.2.cmp(&b.2)))
+            .map_or(SupportedFiletype::Svg, |(filetype, _, _)| filetype)
+    }
+
+    /// Works out the filetype a request wanted from the extension of the last
+    /// segment of its path, for responses that aren't produced by a route
+    /// that parsed a `ScieldRequest` itself, such as errors. Like a
+    /// `ScieldRequest`'s, it's `None` if it should be negotiated instead.
+    pub fn of_request(request: &Request<'_>) -> Option<SupportedFiletype> {
+        request
+            .uri()
+            .path()
+            .segments()
+            .last()
+            .and_then(|s| s.rsplit_once('.'))
+            .and_then(|(_, e)| SupportedFiletype::from_extension(e))
+    }
 }
 
 pub struct ScieldRequest<T: FromStr> {
     pub body: T,
-    pub filetype: SupportedFiletype,
+    /// The requested filetype, or `None` if it should be negotiated from the
+    /// request's `Accept` header when responding.
+    pub filetype: Option<SupportedFiletype>,
+}
+
+/// Error for values that must be one of a fixed set of variants, such as
+/// licences or code styles.
+#[derive(Debug)]
+pub struct UnknownVariant {
+    pub kind: &'static str,
+    pub value: String,
+    pub expected: &'static [&'static str],
+}
+
+impl fmt::Display for UnknownVariant {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "unknown {} {}, expected one of {}",
+            self.kind,
+            self.value,
+            self.expected.join(", ")
+        )
+    }
 }
 
 #[derive(Debug)]
 pub enum ScieldRequestError {
     InvalidBody,
-    InvalidFiletype,
+    UnsupportedFiletype(String),
+    UnknownVariant(UnknownVariant),
+    /// The request named an upstream host that isn't configured.
+    UnknownHost(String),
 }
 
-impl<'r, T: FromStr> FromParam<'r> for ScieldRequest<T> {
-    type Error = ScieldRequestError;
+impl ScieldRequestError {
+    fn expected(&self) -> Option<&'static [&'static str]> {
+        match self {
+            ScieldRequestError::InvalidBody => None,
+            ScieldRequestError::UnsupportedFiletype(_) => Some(SupportedFiletype::EXTENSIONS),
+            ScieldRequestError::UnknownVariant(e) => Some(e.expected),
+            ScieldRequestError::UnknownHost(_) => None,
+        }
+    }
+}
 
-    fn from_param(param: &'r str) -> Result<Self, Self::Error> {
-        let path: PathBuf = PathBuf::from(param);
-        let stem = path.file_stem();
+impl fmt::Display for ScieldRequestError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ScieldRequestError::InvalidBody => write!(f, "invalid request"),
+            ScieldRequestError::UnsupportedFiletype(extension) => write!(
+                f,
+                "unsupported filetype {}, expected one of {}",
+                extension,
+                SupportedFiletype::EXTENSIONS.join(", ")
+            ),
+            ScieldRequestError::UnknownVariant(e) => e.fmt(f),
+            ScieldRequestError::UnknownHost(host) => write!(f, "unknown host {}", host),
+        }
+    }
+}
 
-        if let Some(b) = stem {
-            if let Ok(body) = T::from_str(b.to_str().unwrap()) {
-                if param.ends_with(".png") {
-                    Ok(ScieldRequest {
-                        body,
-                        filetype: SupportedFiletype::Png,
-                    })
-                } else if param.ends_with(".svg") {
-                    Ok(ScieldRequest {
-                        body,
-                        filetype: SupportedFiletype::Svg,
-                    })
-                } else if param.ends_with(".txt") {
-                    Ok(ScieldRequest {
-                        body,
-                        filetype: SupportedFiletype::Txt,
-                    })
-                } else {
-                    Err(ScieldRequestError::InvalidFiletype)
+impl From<Infallible> for ScieldRequestError {
+    fn from(e: Infallible) -> ScieldRequestError {
+        match e {}
+    }
+}
+
+impl From<UnknownVariant> for ScieldRequestError {
+    fn from(e: UnknownVariant) -> ScieldRequestError {
+        ScieldRequestError::UnknownVariant(e)
+    }
+}
+
+impl<T: FromStr> ScieldRequest<T>
+where
+    T::Err: Into<ScieldRequestError>,
+{
+    /// Splits a requested value into its body and filetype. A trailing
+    /// extension is only treated as a filetype if it's one we support, so
+    /// dotted values such as `v1.2.3` or `socket.io` are kept whole and have
+    /// their filetype negotiated instead. Well-known image extensions we don't
+    /// render, such as `.gif`, are refused rather than kept in the body.
+    fn parse(value: &str) -> Result<Self, ScieldRequestError> {
+        let extension = value.rsplit_once('.').filter(|(_, e)| !e.contains('/'));
+
+        if let Some((stem, extension)) = extension {
+            if let Some(filetype) = SupportedFiletype::from_extension(extension) {
+                if stem.is_empty() {
+                    return Err(ScieldRequestError::InvalidBody);
                 }
-            } else {
-                Err(ScieldRequestError::InvalidBody)
+                return match T::from_str(stem) {
+                    Ok(body) => Ok(ScieldRequest {
+                        body,
+                        filetype: Some(filetype),
+                    }),
+                    Err(e) => Err(e.into()),
+                };
             }
-        } else {
-            Err(ScieldRequestError::InvalidBody)
         }
+
+        if value.is_empty() {
+            return Err(ScieldRequestError::InvalidBody);
+        }
+
+        if let Some((_, extension)) = extension {
+            if SupportedFiletype::UNSUPPORTED_EXTENSIONS.contains(&extension) {
+                return Err(ScieldRequestError::UnsupportedFiletype(
+                    extension.to_string(),
+                ));
+            }
+        }
+
+        match (T::from_str(value), extension) {
+            (Ok(body), _) => Ok(ScieldRequest {
+                body,
+                filetype: None,
+            }),
+            (Err(_), Some((_, extension))) => Err(ScieldRequestError::UnsupportedFiletype(
+                extension.to_string(),
+            )),
+            (Err(e), None) => Err(e.into()),
+        }
+    }
+}
+
+impl<'r, T: FromStr> FromParam<'r> for ScieldRequest<T>
+where
+    T::Err: Into<ScieldRequestError>,
+{
+    type Error = ScieldRequestError;
+
+    fn from_param(param: &'r str) -> Result<Self, Self::Error> {
+        ScieldRequest::parse(param)
     }
 }
 
+/// Allows a scield request to span several path segments, for values that may
+/// themselves contain slashes such as branch names.
+impl<'r, T: FromStr> FromSegments<'r> for ScieldRequest<T>
+where
+    T::Err: Into<ScieldRequestError>,
+{
+    type Error = ScieldRequestError;
+
+    fn from_segments(segments: Segments<'r, Path>) -> Result<Self, Self::Error> {
+        ScieldRequest::parse(&segments.collect::<Vec<_>>().join("/"))
+    }
+}
+
+/// Scield Options
+/// ==============
+
+#[derive(FromFormField, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Theme {
+    Dark,
+    Light,
+}
+
+#[derive(FromFormField, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Style {
+    Flat,
+    Rounded,
+}
+
+/// Presentation options shared by every scield, taken from the request's query
+/// string, e.g. `?theme=light&style=rounded&label=Crate`. Values that can't be
+/// parsed are ignored in favour of the defaults.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ScieldOptions {
+    pub theme: Theme,
+    pub style: Style,
+    /// Replaces the scield's own label. An empty label removes it entirely.
+    /// Labels are cut short at `MAX_LABEL_LEN` characters.
+    pub label: Option<String>,
+    /// Scale factor applied to the rendered SVG and PNG dimensions.
+    pub scale: f32,
+    /// Background colour override, as a hex colour without the leading `#`.
+    pub background: Option<String>,
+    /// Foreground colour override, as a hex colour without the leading `#`.
+    pub foreground: Option<String>,
+    /// Upper bound, in seconds, on how long clients may cache the response.
+    pub max_age: Option<u64>,
+}
+
+impl Default for ScieldOptions {
+    fn default() -> ScieldOptions {
+        ScieldOptions {
+            theme: Theme::Dark,
+            style: Style::Flat,
+            label: None,
+            scale: 1.0,
+            background: None,
+            foreground: None,
+            max_age: None,
+        }
+    }
+}
+
+impl ScieldOptions {
+    const MIN_SCALE: f32 = 0.25;
+    const MAX_SCALE: f32 = 10.0;
+    const MAX_LABEL_LEN: usize = 64;
+
+    /// Returns the options for the given request, parsing them from the query
+    /// string the first time they're asked for. The theme and style default to
+    /// those configured, if any.
+    pub fn of<'r>(request: &'r Request<'_>) -> &'r ScieldOptions {
+        request.local_cache(|| {
+            let mut defaults = ScieldOptions::default();
+            if let Some(config) = request.rocket().state::<ScieldsConfig>() {
+                defaults.theme = config.theme;
+                defaults.style = config.style;
+            }
+            ScieldOptions {
+                theme: query_value(request, "theme").unwrap_or(defaults.theme),
+                style: query_value(request, "style").unwrap_or(defaults.style),
+                label: query_value::<String>(request, "label")
+                    .map(|l| l.chars().take(Self::MAX_LABEL_LEN).collect()),
+                scale: query_value::<f32>(request, "scale")
+                    .filter(|s| s.is_finite())
+                    .map(|s| s.clamp(Self::MIN_SCALE, Self::MAX_SCALE))
+                    .unwrap_or(defaults.scale),
+                background: query_value(request, "background").and_then(hex_colour),
+                foreground: query_value(request, "foreground").and_then(hex_colour),
+                max_age: query_value(request, "max_age"),
+            }
+        })
+    }
+
+    fn colours(&self) -> (String, String) {
+        let (background, foreground) = match self.theme {
+            Theme::Dark => ("282828", "F2F2F2"),
+            Theme::Light => ("F2F2F2", "282828"),
+        };
+        (
+            self.background.as_deref().unwrap_or(background).to_string(),
+            self.foreground.as_deref().unwrap_or(foreground).to_string(),
+        )
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for &'r ScieldOptions {
+    type Error = Infallible;
+
+    async fn from_request(request: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        request::Outcome::Success(ScieldOptions::of(request))
+    }
+}
+
+fn query_value<'r, T: FromForm<'r>>(request: &'r Request<'_>, name: &str) -> Option<T> {
+    request.query_value(name).and_then(Result::ok)
+}
+
+fn hex_colour(colour: String) -> Option<String> {
+    let colour = colour.trim_start_matches('#');
+    let valid = matches!(colour.len(), 3 | 6) && colour.chars().all(|c| c.is_ascii_hexdigit());
+    valid.then(|| colour.to_string())
+}
+
 /// Scield Traits
 /// =============
 
 /// Trait for a renderable scield. A scield must implement this trait to turn
 /// a given value into a scieldic representation, usually with some prefix and
 /// transformation of the input value.
+#[allow(clippy::empty_line_after_doc_comments)]
 pub trait RenderableScield<T: ToString> {
-    fn render(&self, value: &T) -> String;
+    /// The label shown before the scield's value, if any.
+    fn label(&self) -> Option<&str>;
+
+    /// Renders the given value, without the label.
+    fn message(&self, value: &T) -> String;
+
+    fn render(&self, value: &T, options: &ScieldOptions) -> String {
+        let label = options.label.as_deref().or_else(|| self.label());
+        match label {
+            Some(l) if !l.is_empty() => format!("{} :: {}", l, self.message(value)),
+            _ => self.message(value),
+        }
+    }
 }
 
 /// Scield
 /// ======
 
+#[allow(clippy::empty_line_after_doc_comments)]
 pub struct Scield<A: ToString, T: RenderableScield<A>> {
     pub scield: T,
     pub value: A,
-    pub filetype: SupportedFiletype,
+    pub filetype: Option<SupportedFiletype>,
 }
 
+/// The most pixels a PNG scield is rendered with, whatever its scale, so that
+/// long values or labels at a large scale can't exhaust memory.
+const MAX_PNG_PIXELS: f32 = 4_000_000.0;
+
 impl<A: ToString, T: RenderableScield<A>> Scield<A, T> {
-    fn to_svg(&self) -> String {
-        let value = self.scield.render(&self.value);
+    fn to_svg(&self, options: &ScieldOptions) -> String {
+        let value = self.scield.render(&self.value, options);
+        let (background, foreground) = options.colours();
         let mut svg: String = "".to_string();
         let width = (&value.len() * 7) + 32;
 
         let head = format!(
-            r#"<svg baseProfile="full" height="41px" version="1.1" width="{}px" xmlns="http://www.w3.org/2000/svg" xmlns:ev="http://www.w3.org/2001/xml-events" xmlns:xlink="http://www.w3.org/1999/xlink">"#,
-            width
+            r#"<svg baseProfile="full" height="{}px" version="1.1" viewBox="0 0 {} 41" width="{}px" xmlns="http://www.w3.org/2000/svg" xmlns:ev="http://www.w3.org/2001/xml-events" xmlns:xlink="http://www.w3.org/1999/xlink">"#,
+            41.0 * options.scale,
+            width,
+            width as f32 * options.scale
         );
         svg.push_str(&head);
 
-        svg.push_str(r##"<rect fill="#282828" height="100%" width="100%" x="0" y="0" />"##);
+        let rounding = match options.style {
+            Style::Flat => "",
+            Style::Rounded => r#" rx="4""#,
+        };
+        let rect = format!(
+            r##"<rect fill="#{}" height="100%" width="100%" x="0" y="0"{} />"##,
+            background, rounding
+        );
+        svg.push_str(&rect);
         let b = format!(
-            r##"<text fill="#F2F2F2" font-family="Inconsolata Nerd Font, Inconsolata, monospace" font-size="140" textLength="{}" transform="scale(.1)" x="160" y="240">{}</text>"##,
+            r##"<text fill="#{}" font-family="Inconsolata Nerd Font, Inconsolata, monospace" font-size="140" textLength="{}" transform="scale(.1)" x="160" y="240">{}</text>"##,
+            foreground,
             (width * 10) - 320,
-            &value
+            escape_xml(&value)
         );
         svg.push_str(&b);
         svg.push_str("</svg>");
         svg
     }
 
-    fn to_png(&self, opt: &usvg::Options) -> Vec<u8> {
-        let svg = self.to_svg();
-        let rtree = usvg::Tree::from_str(&svg, &opt.to_ref()).unwrap();
+    /// Renders the scield as a PNG, scaled down as far as needed to keep it
+    /// within `MAX_PNG_PIXELS`, or `None` if it couldn't be rendered.
+    fn to_png(&self, opt: &usvg::Options, options: &ScieldOptions) -> Option<Vec<u8>> {
+        let width = self.scield.render(&self.value, options).len() * 7 + 32;
+        let largest_scale = (MAX_PNG_PIXELS / (width as f32 * 41.0)).sqrt();
+        let options = ScieldOptions {
+            scale: options.scale.min(largest_scale),
+            ..options.clone()
+        };
+
+        let svg = self.to_svg(&options);
+        let rtree = usvg::Tree::from_str(&svg, &opt.to_ref()).ok()?;
         let pixmap_size = rtree.svg_node().size.to_screen_size();
-        let mut pixmap = tiny_skia::Pixmap::new(pixmap_size.width(), pixmap_size.height()).unwrap();
+        let mut pixmap = tiny_skia::Pixmap::new(pixmap_size.width(), pixmap_size.height())?;
         resvg::render(
             &rtree,
             usvg::FitTo::Original,
             tiny_skia::Transform::default(),
             pixmap.as_mut(),
-        )
-        .unwrap();
-        pixmap.encode_png().unwrap()
+        )?;
+        pixmap.encode_png().ok()
     }
 }
 
-#[rocket::async_trait]
-impl<'r, A: ToString, T: RenderableScield<A>> Responder<'r, 'static> for Scield<A, T> {
-    fn respond_to(self, request: &'r Request<'_>) -> response::Result<'static> {
-        match self.filetype {
+impl<A: ToString, T: RenderableScield<A>> Scield<A, T> {
+    /// Renders the scield into a response, along with the headers telling
+    /// clients how long they can cache it for.
+    fn render_response(&self, request: &Request<'_>) -> response::Result<'static> {
+        let options = ScieldOptions::of(request);
+        let negotiated = self.filetype.is_none();
+        let filetype = self
+            .filetype
+            .unwrap_or_else(|| SupportedFiletype::negotiate(request.accept()));
+
+        let timer = RENDER_SECONDS
+            .with_label_values(&[filetype.extension()])
+            .start_timer();
+        let (content_type, body) = match filetype {
             SupportedFiletype::Png => {
                 let opt: &usvg::Options = request.rocket().state().unwrap();
-                let png = self.to_png(opt);
-                Response::build()
-                    .header(ContentType::PNG)
-                    .sized_body(png.len(), Cursor::new(png))
-                    .ok()
+                let png = self.to_png(opt, options).ok_or_else(|| {
+                    log::warn!("Failed to render a PNG for {}", request.uri());
+                    Status::InternalServerError
+                })?;
+                (ContentType::PNG, png)
             }
-            SupportedFiletype::Svg => {
-                let svg = self.to_svg();
-                Response::build()
-                    .header(ContentType::SVG)
-                    .sized_body(svg.len(), Cursor::new(svg))
-                    .ok()
+            SupportedFiletype::Svg => (ContentType::SVG, self.to_svg(options).into_bytes()),
+            SupportedFiletype::Txt => (
+                ContentType::Plain,
+                self.scield.render(&self.value, options).into_bytes(),
+            ),
+            SupportedFiletype::Json => {
+                let label = options.label.as_deref().or_else(|| self.scield.label());
+                let json = serde_json::json!({
+                    "label": label.filter(|l| !l.is_empty()),
+                    "message": self.scield.message(&self.value),
+                    "text": self.scield.render(&self.value, options),
+                });
+                (ContentType::JSON, json.to_string().into_bytes())
+            }
+        };
+        timer.observe_duration();
+
+        let mut response = Response::build();
+        if negotiated {
+            response.header(Header::new("Vary", "Accept"));
+        }
+        Ok(response
+            .header(content_type)
+            .merge(caching_headers(request, &body))
+            .sized_body(body.len(), Cursor::new(body))
+            .finalize())
+    }
+}
+
+#[rocket::async_trait]
+impl<'r, A: ToString, T: RenderableScield<A>> Responder<'r, 'static> for Scield<A, T> {
+    fn respond_to(self, request: &'r Request<'_>) -> response::Result<'static> {
+        let mut response = self.render_response(request)?;
+
+        let etag = response.headers().get_one("ETag").unwrap_or_default();
+        let not_modified = request
+            .headers()
+            .get("If-None-Match")
+            .flat_map(|h| h.split(','))
+            .map(|t| t.trim().trim_start_matches("W/"))
+            .any(|t| t == "*" || t == etag);
+
+        if not_modified {
+            response.set_status(Status::NotModified);
+            response.set_sized_body(0, Cursor::new(Vec::new()));
+            response.remove_header("Content-Type");
+        }
+
+        Ok(response)
+    }
+}
+
+/// How long clients may cache scields that don't depend on any upstream
+/// payload, such as licences.
+const STATIC_MAX_AGE: Duration = Duration::from_secs(86400);
+
+/// The `Cache-Control` header for a response with the given status. The max
+/// age runs until the first of the upstream payloads used by the request goes
+/// stale, capped by the `max_age` option, which for failed fetches is their
+/// negative TTL. Errors that didn't come of an upstream payload aren't cached,
+/// so that they're gone as soon as whatever caused them is, and nor is a
+/// throttled client's, which is gone as soon as their budget refills.
+fn cache_control(request: &Request<'_>, status: Status) -> String {
+    let max_age = match Freshness::of(request).max_age() {
+        _ if status == Status::TooManyRequests => return "no-store".to_string(),
+        Some(max_age) => max_age,
+        None if status.class().is_success() => STATIC_MAX_AGE,
+        None => return "no-store".to_string(),
+    };
+
+    let mut max_age = max_age.as_secs();
+    if let Some(limit) = ScieldOptions::of(request).max_age {
+        max_age = max_age.min(limit);
+    }
+    format!("max-age={}", max_age)
+}
+
+/// Builds the `Cache-Control`, `Last-Modified` and `ETag` headers for a
+/// rendered scield.
+fn caching_headers(request: &Request<'_>, body: &[u8]) -> Response<'static> {
+    let freshness = Freshness::of(request);
+
+    let mut hasher = DefaultHasher::new();
+    body.hash(&mut hasher);
+
+    let mut response = Response::build();
+    response
+        .raw_header("Cache-Control", cache_control(request, Status::Ok))
+        .raw_header("ETag", format!("\"{:016x}\"", hasher.finish()));
+    if let Some(last_modified) = freshness.last_modified() {
+        response.raw_header("Last-Modified", httpdate::fmt_http_date(last_modified));
+    }
+    response.finalize()
+}
+
+fn escape_xml(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Scield Errors
+/// =============
+///
+/// Errors returned by routes, rendered as an error scield in the requested
+/// filetype, or as a structured body when JSON is requested.
+
+#[allow(clippy::empty_line_after_doc_comments)]
+const ERROR_SCIELD: TextScield = TextScield {
+    prefix: "Error",
+    suffix: None,
+};
+
+#[derive(Debug)]
+pub enum ScieldError {
+    Request(ScieldRequestError),
+    /// No value could be found for the scield, e.g. the project doesn't exist.
+    NotFound,
+    /// The upstream couldn't provide a value right now.
+    Upstream(FetchError),
+}
+
+impl ScieldError {
+    fn status(&self) -> Status {
+        match self {
+            ScieldError::Request(_) => Status::BadRequest,
+            ScieldError::NotFound | ScieldError::Upstream(FetchError::NotFound) => Status::NotFound,
+            ScieldError::Upstream(FetchError::Forbidden) => Status::Forbidden,
+            ScieldError::Upstream(FetchError::RateLimited) => Status::ServiceUnavailable,
+            ScieldError::Upstream(FetchError::Throttled) => Status::TooManyRequests,
+            ScieldError::Upstream(
+                FetchError::ServerError | FetchError::Decode | FetchError::Transient,
+            ) => Status::BadGateway,
+        }
+    }
+
+    fn kind(&self) -> &'static str {
+        match self {
+            ScieldError::Request(ScieldRequestError::InvalidBody) => "invalid_request",
+            ScieldError::Request(ScieldRequestError::UnsupportedFiletype(_)) => {
+                "unsupported_filetype"
             }
-            SupportedFiletype::Txt => {
-                let value = self.scield.render(&self.value);
-                Response::build()
-                    .header(ContentType::Plain)
-                    .sized_body(value.len(), Cursor::new(value))
-                    .ok()
+            ScieldError::Request(ScieldRequestError::UnknownVariant(_)) => "unknown_variant",
+            ScieldError::Request(ScieldRequestError::UnknownHost(_)) => "unknown_host",
+            ScieldError::NotFound | ScieldError::Upstream(FetchError::NotFound) => "not_found",
+            ScieldError::Upstream(FetchError::Forbidden) => "forbidden",
+            ScieldError::Upstream(FetchError::RateLimited) => "rate_limited",
+            ScieldError::Upstream(FetchError::ServerError) => "upstream_error",
+            ScieldError::Upstream(FetchError::Decode) => "invalid_payload",
+            ScieldError::Upstream(FetchError::Transient) => "upstream_unavailable",
+            ScieldError::Upstream(FetchError::Throttled) => "too_many_requests",
+        }
+    }
+}
+
+impl fmt::Display for ScieldError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ScieldError::Request(e) => e.fmt(f),
+            ScieldError::NotFound | ScieldError::Upstream(FetchError::NotFound) => {
+                write!(f, "not found")
             }
+            ScieldError::Upstream(FetchError::Forbidden) => write!(f, "forbidden"),
+            ScieldError::Upstream(FetchError::RateLimited) => write!(f, "rate limited"),
+            ScieldError::Upstream(FetchError::ServerError) => write!(f, "upstream error"),
+            ScieldError::Upstream(FetchError::Decode) => write!(f, "invalid upstream payload"),
+            ScieldError::Upstream(FetchError::Transient) => write!(f, "upstream unavailable"),
+            ScieldError::Upstream(FetchError::Throttled) => write!(f, "too many requests"),
         }
     }
 }
 
+impl From<ScieldRequestError> for ScieldError {
+    fn from(e: ScieldRequestError) -> ScieldError {
+        ScieldError::Request(e)
+    }
+}
+
+impl From<FetchError> for ScieldError {
+    fn from(e: FetchError) -> ScieldError {
+        ScieldError::Upstream(e)
+    }
+}
+
+impl<'r> Responder<'r, 'static> for ScieldError {
+    fn respond_to(self, request: &'r Request<'_>) -> response::Result<'static> {
+        log::info!(
+            request_id = RequestId::of(request).0.as_str(),
+            error = self.kind();
+            "No scield for {}: {}",
+            request.uri(),
+            self
+        );
+        let requested = SupportedFiletype::of_request(request);
+        let filetype = requested.unwrap_or_else(|| SupportedFiletype::negotiate(request.accept()));
+
+        let mut response = match filetype {
+            SupportedFiletype::Json => {
+                let expected = match &self {
+                    ScieldError::Request(e) => e.expected(),
+                    _ => None,
+                };
+                let json = serde_json::json!({
+                    "error": self.kind(),
+                    "message": self.to_string(),
+                    "expected": expected,
+                })
+                .to_string();
+                let mut response = Response::build();
+                if requested.is_none() {
+                    response.header(Header::new("Vary", "Accept"));
+                }
+                response
+                    .header(ContentType::JSON)
+                    .merge(caching_headers(request, json.as_bytes()))
+                    .sized_body(json.len(), Cursor::new(json))
+                    .finalize()
+            }
+            _ => Scield {
+                scield: ERROR_SCIELD,
+                value: self.to_string(),
+                filetype: requested,
+            }
+            .render_response(request)?,
+        };
+
+        response.set_status(self.status());
+        response.set_raw_header("Cache-Control", cache_control(request, self.status()));
+        Ok(response)
+    }
+}
+
 /// Text Scield
 /// ===========
 ///
 /// A simple scield for returning an arbitrary string value.
 
+#[allow(clippy::empty_line_after_doc_comments)]
 pub struct TextScield {
     pub prefix: &'static str,
     pub suffix: Option<&'static str>,
 }
 
+impl TextScield {
+    fn suffixed(&self, value: String) -> String {
+        match &self.suffix {
+            Some(s) => format!("{} {}", value, s),
+            None => value,
+        }
+    }
+}
+
 impl RenderableScield<String> for TextScield {
-    fn render(&self, value: &String) -> String {
-        let prefix = format!("{} :: ", &self.prefix);
-        let suffix = match &self.suffix {
-            Some(s) => format!(" {}", s),
-            None => String::from(""),
-        };
-        format!("{}{}{}", prefix, &value, suffix)
+    fn label(&self) -> Option<&str> {
+        Some(self.prefix)
+    }
+
+    fn message(&self, value: &String) -> String {
+        self.suffixed(value.clone())
     }
 }
 
 impl RenderableScield<f64> for TextScield {
-    fn render(&self, value: &f64) -> String {
-        let prefix = format!("{} :: ", &self.prefix);
-        let suffix = match &self.suffix {
-            Some(s) => format!(" {}", s),
-            None => String::from(""),
-        };
-        format!("{}{}{}", prefix, readable_number(*value), suffix)
+    fn label(&self) -> Option<&str> {
+        Some(self.prefix)
+    }
+
+    fn message(&self, value: &f64) -> String {
+        self.suffixed(readable_number(*value))
     }
 }
 
@@ -188,6 +791,7 @@ fn readable_number(number: f64) -> String {
     if number == 0.0 {
         number.to_string()
     } else {
+        #[allow(clippy::useless_vec)]
         let units = vec!["", "k", "m", "bn"];
         let magnitude: usize = cmp::min(number.abs().log(1000.0).floor() as usize, units.len() - 1);
         let amnt = number.abs() / (1000.0_f64.powf(magnitude as f64));
@@ -216,29 +820,30 @@ fn readable_number(number: f64) -> String {
 ///
 /// A scield for returning a value from a predefined set of possible values.
 
+#[allow(clippy::empty_line_after_doc_comments)]
 pub struct StateScield {
     pub prefix: Option<&'static str>,
     pub suffix: Option<&'static str>,
 }
 
 impl<A: ToString> RenderableScield<A> for StateScield {
-    fn render(&self, value: &A) -> String {
-        let prefix = match &self.prefix {
-            Some(s) => format!("{} :: ", &s),
-            None => "".to_string(),
-        };
-        let suffix = match &self.suffix {
-            Some(s) => format!(" {}", s),
-            None => "".to_string(),
-        };
+    fn label(&self) -> Option<&str> {
+        self.prefix
+    }
+
+    fn message(&self, value: &A) -> String {
         let value = value.to_string();
-        format!("{}{}{}", prefix, value, suffix)
+        match &self.suffix {
+            Some(s) => format!("{} {}", value, s),
+            None => value,
+        }
     }
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
+    use rocket::local::blocking::Client;
 
     #[test]
     fn test_readable_number() {
@@ -261,4 +866,238 @@ mod test {
         assert_eq!(readable_number(-123_456_789.0), "-123m");
         assert_eq!(readable_number(-1_234_567_891.0), "-1bn");
     }
+
+    #[test]
+    fn test_scield_request_extension() {
+        let request: ScieldRequest<String> = ScieldRequest::from_param("scieldas.png").unwrap();
+        assert_eq!(request.body, "scieldas");
+        assert_eq!(request.filetype, Some(SupportedFiletype::Png));
+
+        let request: ScieldRequest<String> = ScieldRequest::from_param("scieldas.json").unwrap();
+        assert_eq!(request.filetype, Some(SupportedFiletype::Json));
+
+        let request: ScieldRequest<String> = ScieldRequest::from_param("scieldas").unwrap();
+        assert_eq!(request.body, "scieldas");
+        assert_eq!(request.filetype, None);
+
+        assert!(ScieldRequest::<String>::from_param(".svg").is_err());
+        assert!(ScieldRequest::<String>::from_param("").is_err());
+        assert!(matches!(
+            ScieldRequest::<Colour>::from_param("red.gif"),
+            Err(ScieldRequestError::UnsupportedFiletype(e)) if e == "gif"
+        ));
+        assert!(matches!(
+            ScieldRequest::<String>::from_param("scieldas.gif"),
+            Err(ScieldRequestError::UnsupportedFiletype(e)) if e == "gif"
+        ));
+        assert!(matches!(
+            ScieldRequest::<String>::from_param("scieldas.jpeg"),
+            Err(ScieldRequestError::UnsupportedFiletype(e)) if e == "jpeg"
+        ));
+        assert!(matches!(
+            ScieldRequest::<Colour>::from_param("blue.svg"),
+            Err(ScieldRequestError::UnknownVariant(e)) if e.value == "blue"
+        ));
+        assert!(matches!(
+            ScieldRequest::<Colour>::from_param("blue"),
+            Err(ScieldRequestError::UnknownVariant(_))
+        ));
+    }
+
+    #[test]
+    fn test_scield_request_dotted_body() {
+        let request: ScieldRequest<String> = ScieldRequest::from_param("v1.2.3.svg").unwrap();
+        assert_eq!(request.body, "v1.2.3");
+        assert_eq!(request.filetype, Some(SupportedFiletype::Svg));
+
+        let request: ScieldRequest<String> = ScieldRequest::from_param("v1.2.3").unwrap();
+        assert_eq!(request.body, "v1.2.3");
+        assert_eq!(request.filetype, None);
+
+        let request: ScieldRequest<String> = ScieldRequest::from_param("socket.io.txt").unwrap();
+        assert_eq!(request.body, "socket.io");
+        assert_eq!(request.filetype, Some(SupportedFiletype::Txt));
+
+        let request: ScieldRequest<String> = ScieldRequest::from_param("1.5.png").unwrap();
+        assert_eq!(request.body, "1.5");
+        assert_eq!(request.filetype, Some(SupportedFiletype::Png));
+    }
+
+    #[derive(Debug)]
+    struct Colour;
+
+    impl FromStr for Colour {
+        type Err = UnknownVariant;
+
+        fn from_str(s: &str) -> Result<Colour, UnknownVariant> {
+            match s {
+                "red" => Ok(Colour),
+                _ => Err(UnknownVariant {
+                    kind: "colour",
+                    value: s.to_string(),
+                    expected: &["red"],
+                }),
+            }
+        }
+    }
+
+    #[get("/<value..>")]
+    fn echo(value: ScieldRequest<String>) -> String {
+        format!("{} {:?}", value.body, value.filetype)
+    }
+
+    #[test]
+    fn test_scield_request_segments() {
+        let rocket = rocket::build().mount("/", routes![echo]);
+        let client = Client::tracked(rocket).unwrap();
+        let get = |uri: &str| {
+            client
+                .get(uri.to_string())
+                .dispatch()
+                .into_string()
+                .unwrap()
+        };
+
+        assert_eq!(get("/main.svg"), "main Some(Svg)");
+        assert_eq!(get("/release/1.0.svg"), "release/1.0 Some(Svg)");
+        assert_eq!(get("/release/1.0"), "release/1.0 None");
+        assert_eq!(get("/feature/a.b/c.png"), "feature/a.b/c Some(Png)");
+        assert_eq!(get("/release%2F1.0.txt"), "release/1.0 Some(Txt)");
+    }
+
+    #[test]
+    fn test_negotiate_filetype() {
+        let negotiate = |accept: &str| SupportedFiletype::negotiate(Some(&accept.parse().unwrap()));
+
+        assert_eq!(SupportedFiletype::negotiate(None), SupportedFiletype::Svg);
+        assert_eq!(negotiate("*/*"), SupportedFiletype::Svg);
+        assert_eq!(negotiate("image/png"), SupportedFiletype::Png);
+        assert_eq!(negotiate("text/plain"), SupportedFiletype::Txt);
+        assert_eq!(negotiate("application/json"), SupportedFiletype::Json);
+        assert_eq!(negotiate("text/html"), SupportedFiletype::Svg);
+        assert_eq!(
+            negotiate("text/plain;q=0.5, image/png;q=0.9"),
+            SupportedFiletype::Png
+        );
+        assert_eq!(
+            negotiate("image/webp,image/svg+xml,image/*,*/*;q=0.8"),
+            SupportedFiletype::Svg
+        );
+        assert_eq!(
+            negotiate("image/svg+xml;q=0, image/png"),
+            SupportedFiletype::Png
+        );
+        assert_eq!(
+            negotiate("image/svg+xml;q=0, */*;q=0.8"),
+            SupportedFiletype::Png
+        );
+        assert_eq!(negotiate("image/png;q=0"), SupportedFiletype::Svg);
+        // The most specific range decides, however the wildcards are weighed.
+        assert_eq!(negotiate("image/*;q=0, image/png"), SupportedFiletype::Png);
+        assert_eq!(
+            negotiate("*/*;q=0, application/json;q=0.1"),
+            SupportedFiletype::Json
+        );
+        assert_eq!(
+            negotiate("image/*;q=0.5, image/svg+xml;q=0, text/*;q=0.2"),
+            SupportedFiletype::Png
+        );
+    }
+
+    #[test]
+    fn test_scield_options() {
+        let client = Client::untracked(rocket::build()).unwrap();
+
+        let request = client.get("/");
+        assert_eq!(
+            *ScieldOptions::of(request.inner()),
+            ScieldOptions::default()
+        );
+
+        let request = client.get(
+            "/?theme=light&style=rounded&label=Crate&scale=20&background=%23ff0000&foreground=zzz&max_age=60",
+        );
+        let options = ScieldOptions::of(request.inner());
+        assert_eq!(options.theme, Theme::Light);
+        assert_eq!(options.style, Style::Rounded);
+        assert_eq!(options.label.as_deref(), Some("Crate"));
+        assert_eq!(options.scale, ScieldOptions::MAX_SCALE);
+        assert_eq!(options.background.as_deref(), Some("ff0000"));
+        assert_eq!(options.foreground, None);
+        assert_eq!(options.max_age, Some(60));
+
+        let label = "a".repeat(1000);
+        let request = client.get(format!("/?label={}", label));
+        let options = ScieldOptions::of(request.inner());
+        assert_eq!(
+            options.label.as_deref(),
+            Some(&label[..ScieldOptions::MAX_LABEL_LEN])
+        );
+
+        let request = client.get("/?theme=neon&scale=NaN");
+        let options = ScieldOptions::of(request.inner());
+        assert_eq!(options.theme, Theme::Dark);
+        assert_eq!(options.scale, 1.0);
+
+        // Configured defaults give way to the query string.
+        let rocket = rocket::build().manage(ScieldsConfig {
+            theme: Theme::Light,
+            style: Style::Rounded,
+        });
+        let client = Client::untracked(rocket).unwrap();
+        let request = client.get("/?style=flat");
+        let options = ScieldOptions::of(request.inner());
+        assert_eq!(options.theme, Theme::Light);
+        assert_eq!(options.style, Style::Flat);
+    }
+
+    #[test]
+    fn test_render_with_options() {
+        let scield = Scield {
+            scield: TextScield {
+                prefix: "Stars",
+                suffix: None,
+            },
+            value: 1234.0,
+            filetype: None,
+        };
+
+        let options = ScieldOptions::default();
+        assert_eq!(scield.scield.render(&scield.value, &options), "Stars :: 1k");
+
+        let options = ScieldOptions {
+            label: Some("<Stargazers>".to_string()),
+            theme: Theme::Light,
+            ..ScieldOptions::default()
+        };
+        let svg = scield.to_svg(&options);
+        assert!(svg.contains("&lt;Stargazers&gt; :: 1k"));
+        assert!(svg.contains(r##"fill="#F2F2F2""##));
+
+        let options = ScieldOptions {
+            label: Some(String::new()),
+            ..ScieldOptions::default()
+        };
+        assert_eq!(scield.scield.render(&scield.value, &options), "1k");
+    }
+
+    #[test]
+    fn test_png_size() {
+        let scield = Scield {
+            scield: TextScield {
+                prefix: "Version",
+                suffix: None,
+            },
+            value: "1".repeat(2000),
+            filetype: None,
+        };
+        let options = ScieldOptions {
+            scale: ScieldOptions::MAX_SCALE,
+            ..ScieldOptions::default()
+        };
+
+        let png = scield.to_png(&usvg::Options::default(), &options).unwrap();
+        let pixmap = tiny_skia::Pixmap::decode_png(&png).unwrap();
+        assert!((pixmap.width() * pixmap.height()) as f32 <= MAX_PNG_PIXELS * 1.01);
+    }
 }
@@ -1,4 +1,6 @@
-use crate::scieldas::{Scield, ScieldRequest, StateScield};
+use crate::scieldas::{
+    Scield, ScieldError, ScieldRequest, ScieldRequestError, StateScield, UnknownVariant,
+};
 use std::str::FromStr;
 
 enum PythonStyle {
@@ -7,20 +9,23 @@ enum PythonStyle {
     AutoPEP8,
 }
 
-struct ParseStyleError;
-
 impl FromStr for PythonStyle {
-    type Err = ParseStyleError;
-    fn from_str(s: &str) -> Result<PythonStyle, ParseStyleError> {
+    type Err = UnknownVariant;
+    fn from_str(s: &str) -> Result<PythonStyle, UnknownVariant> {
         match &s.to_lowercase()[..] {
             "black" => Ok(PythonStyle::Black),
             "yapf" => Ok(PythonStyle::Yapf),
             "autopep8" => Ok(PythonStyle::AutoPEP8),
-            _ => Err(ParseStyleError),
+            _ => Err(UnknownVariant {
+                kind: "code style",
+                value: s.to_string(),
+                expected: &["black", "yapf", "autopep8"],
+            }),
         }
     }
 }
 
+#[allow(clippy::to_string_trait_impl)]
 impl ToString for PythonStyle {
     fn to_string(&self) -> String {
         match &self {
@@ -41,10 +46,14 @@ pub fn routes() -> Vec<rocket::Route> {
 }
 
 #[get("/python/<codestyle>")]
-async fn python_style(codestyle: ScieldRequest<PythonStyle>) -> Scield<PythonStyle, StateScield> {
-    Scield {
+async fn python_style(
+    codestyle: Result<ScieldRequest<PythonStyle>, ScieldRequestError>,
+) -> Result<Scield<PythonStyle, StateScield>, ScieldError> {
+    let codestyle = codestyle?;
+
+    Ok(Scield {
         scield: PYTHON_STYLE_SCIELD,
         value: codestyle.body,
         filetype: codestyle.filetype,
-    }
+    })
 }
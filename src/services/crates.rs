@@ -1,9 +1,27 @@
-use crate::scieldas::{Scield, ScieldRequest, TextScield};
-use crate::utils::get_payload;
-use reqwest::Client;
+use crate::scieldas::{Scield, ScieldError, ScieldRequest, ScieldRequestError, TextScield};
+use crate::utils::{get_payload, FetchError, Upstream};
 use rocket::State;
+use serde::Deserialize;
+use serde_json::Value;
 
-const CRATE_API_URL: &str = "https://crates.io/api/v1/crates/";
+const CRATE_API_URL: &str = "https://crates.io/api/v1/crates";
+
+/// Configuration for the crates.io API, read from the `crates` table of
+/// the configuration.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct CratesConfig {
+    /// The root of the crates API, under which crates are looked up by name.
+    pub api_url: String,
+}
+
+impl Default for CratesConfig {
+    fn default() -> CratesConfig {
+        CratesConfig {
+            api_url: CRATE_API_URL.to_string(),
+        }
+    }
+}
 
 const CRATE_DOWNLOADS_SCIELD: TextScield = TextScield {
     prefix: "Downloads",
@@ -21,17 +39,20 @@ pub fn routes() -> Vec<rocket::Route> {
 
 #[get("/downloads/<crate_name>")]
 pub async fn crate_downloads(
-    client: &State<Client>,
-    crate_name: ScieldRequest<String>,
-) -> Option<Scield<f64, TextScield>> {
-    let request_url = format!("{}/{}", CRATE_API_URL, crate_name.body);
+    upstream: Upstream<'_>,
+    config: &State<CratesConfig>,
+    crate_name: Result<ScieldRequest<String>, ScieldRequestError>,
+) -> Result<Scield<f64, TextScield>, ScieldError> {
+    let crate_name = crate_name?;
+    let request_url = format!("{}/{}", config.api_url, crate_name.body);
 
-    let downloads = get_payload(client, &request_url)
+    let downloads = get_payload(&upstream, "crates_downloads", &request_url)
         .await?
-        .pointer("/crate/downloads")?
-        .as_f64()?;
+        .pointer("/crate/downloads")
+        .and_then(Value::as_f64)
+        .ok_or(FetchError::Decode)?;
 
-    Some(Scield {
+    Ok(Scield {
         scield: CRATE_DOWNLOADS_SCIELD,
         value: downloads,
         filetype: crate_name.filetype,
@@ -40,18 +61,21 @@ pub async fn crate_downloads(
 
 #[get("/downloads/<crate_name>/<version>")]
 pub async fn crate_version_downloads(
-    client: &State<Client>,
+    upstream: Upstream<'_>,
+    config: &State<CratesConfig>,
     crate_name: &str,
-    version: ScieldRequest<String>,
-) -> Option<Scield<f64, TextScield>> {
-    let request_url = format!("{}/{}/{}", CRATE_API_URL, crate_name, version.body);
+    version: Result<ScieldRequest<String>, ScieldRequestError>,
+) -> Result<Scield<f64, TextScield>, ScieldError> {
+    let version = version?;
+    let request_url = format!("{}/{}/{}", config.api_url, crate_name, version.body);
 
-    let downloads = get_payload(client, &request_url)
+    let downloads = get_payload(&upstream, "crates_version_downloads", &request_url)
         .await?
-        .pointer("/version/downloads")?
-        .as_f64()?;
+        .pointer("/version/downloads")
+        .and_then(Value::as_f64)
+        .ok_or(FetchError::Decode)?;
 
-    Some(Scield {
+    Ok(Scield {
         scield: CRATE_DOWNLOADS_SCIELD,
         value: downloads,
         filetype: version.filetype,
@@ -60,21 +84,95 @@ pub async fn crate_version_downloads(
 
 #[get("/version/<crate_name>")]
 pub async fn crate_version(
-    client: &State<Client>,
-    crate_name: ScieldRequest<String>,
-) -> Option<Scield<String, TextScield>> {
-    let request_url = format!("{}/{}", CRATE_API_URL, crate_name.body);
+    upstream: Upstream<'_>,
+    config: &State<CratesConfig>,
+    crate_name: Result<ScieldRequest<String>, ScieldRequestError>,
+) -> Result<Scield<String, TextScield>, ScieldError> {
+    let crate_name = crate_name?;
+    let request_url = format!("{}/{}", config.api_url, crate_name.body);
 
     let version = String::from(
-        get_payload(client, &request_url)
+        get_payload(&upstream, "crates_version", &request_url)
             .await?
-            .pointer("/crate/max_version")?
-            .as_str()?,
+            .pointer("/crate/max_version")
+            .and_then(Value::as_str)
+            .ok_or(FetchError::Decode)?,
     );
 
-    Some(Scield {
+    Ok(Scield {
         scield: CRATE_VERSION_SCIELD,
         value: version,
         filetype: crate_name.filetype,
     })
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cache::{CacheConfig, PayloadCache};
+    use crate::client::{ClientConfig, UpstreamClient};
+    use crate::utils::InFlight;
+    use rocket::http::Accept;
+    use rocket::local::asynchronous::Client;
+    use serde_json::json;
+    use wiremock::matchers::path;
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    #[rocket::async_test]
+    async fn test_dotted_names() {
+        let server = MockServer::start().await;
+        Mock::given(path("/socket.io"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "crate": {"downloads": 1200, "max_version": "v1.2.3"}
+            })))
+            .mount(&server)
+            .await;
+        Mock::given(path("/socket.io/v1.2.3"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "version": {"downloads": 34}
+            })))
+            .mount(&server)
+            .await;
+        let rocket = rocket::build()
+            .manage(UpstreamClient::new(ClientConfig::default(), Vec::new()).unwrap())
+            .manage(PayloadCache::new(CacheConfig::default()))
+            .manage(InFlight::default())
+            .manage(CratesConfig {
+                api_url: server.uri(),
+            })
+            .mount("/crates", routes());
+        let client = Client::tracked(rocket).await.unwrap();
+
+        let response = client.get("/crates/version/socket.io.txt").dispatch();
+        assert_eq!(
+            response.await.into_string().await.unwrap(),
+            "Version :: v1.2.3"
+        );
+
+        let response = client
+            .get("/crates/downloads/socket.io")
+            .header(Accept::Text)
+            .dispatch();
+        assert_eq!(
+            response.await.into_string().await.unwrap(),
+            "Downloads :: 1k"
+        );
+
+        let response = client
+            .get("/crates/downloads/socket.io/v1.2.3.txt")
+            .dispatch();
+        assert_eq!(
+            response.await.into_string().await.unwrap(),
+            "Downloads :: 34"
+        );
+
+        let response = client
+            .get("/crates/downloads/socket.io/v1.2.3")
+            .header(Accept::Text)
+            .dispatch();
+        assert_eq!(
+            response.await.into_string().await.unwrap(),
+            "Downloads :: 34"
+        );
+    }
+}
@@ -1,11 +1,146 @@
-use crate::scieldas::{Scield, ScieldRequest, StateScield, TextScield};
-use crate::utils::get_payload;
-use reqwest::Client;
-use rocket::request::FromParam;
-use rocket::State;
+use crate::cache::PayloadCache;
+use crate::client::{UpstreamClient, Validators};
+use crate::scieldas::{
+    Scield, ScieldError, ScieldRequest, ScieldRequestError, StateScield, TextScield, UnknownVariant,
+};
+use crate::tokens::TokenPool;
+use crate::utils::{get_payload, get_payload_with, FetchError, Fetcher, Upstream};
+use futures::future::FutureExt;
+use reqwest::Url;
+use rocket::http::{RawStr, Status};
+use rocket::request::{self, FromParam, FromRequest, Request};
+use serde::Deserialize;
+use serde_json::{json, Map, Value};
+use std::collections::HashMap;
 use std::str::FromStr;
 
 const GITHUB_API_URL: &str = "https://api.github.com";
+const GITHUB_GRAPHQL_URL: &str = "https://api.github.com/graphql";
+
+/// Fetches all of a repository's counts at once, under the names of the REST
+/// payloads they're served from.
+const REPO_QUERY: &str = "query($owner: String!, $name: String!) {
+  repository(owner: $owner, name: $name) {
+    watchers { totalCount }
+    forkCount
+    stargazerCount
+    issues { totalCount }
+    openIssues: issues(states: OPEN) { totalCount }
+    closedIssues: issues(states: CLOSED) { totalCount }
+    pullRequests { totalCount }
+    openPullRequests: pullRequests(states: OPEN) { totalCount }
+    closedPullRequests: pullRequests(states: [CLOSED, MERGED]) { totalCount }
+  }
+}";
+
+/// Configuration for the GitHub API, read from the `github` table of the
+/// configuration, e.g.
+///
+/// ```toml
+/// [default.github]
+/// tokens = ["ghp_...", "ghp_..."]
+/// graphql = true
+///
+/// [default.github.hosts.acme]
+/// api_url = "https://github.acme.com/api/v3"
+/// graphql_url = "https://github.acme.com/api/graphql"
+/// tokens = ["ghp_..."]
+/// ```
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct GithubConfig {
+    pub api_url: String,
+    /// Whether a repository's counts are fetched together with the GraphQL
+    /// API, which only answers requests authenticated with a token.
+    pub graphql: bool,
+    pub graphql_url: String,
+    /// Tokens to authenticate requests to the GitHub API with, used in turn.
+    pub tokens: Vec<String>,
+    /// Further GitHub hosts, such as GitHub Enterprise Servers, keyed by the
+    /// name requests pick them by with the `host` query parameter.
+    pub hosts: HashMap<String, GithubHost>,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct GithubHost {
+    pub api_url: String,
+    /// The host's GraphQL API, if its counts should be fetched with it.
+    #[serde(default)]
+    pub graphql_url: Option<String>,
+    #[serde(default)]
+    pub tokens: Vec<String>,
+}
+
+impl Default for GithubConfig {
+    fn default() -> GithubConfig {
+        GithubConfig {
+            api_url: GITHUB_API_URL.to_string(),
+            graphql: true,
+            graphql_url: GITHUB_GRAPHQL_URL.to_string(),
+            tokens: Vec::new(),
+            hosts: HashMap::new(),
+        }
+    }
+}
+
+impl GithubConfig {
+    /// A pool of the configured tokens for each host's API.
+    pub fn token_pools(&self) -> Vec<TokenPool> {
+        let hosts = self
+            .hosts
+            .values()
+            .map(|host| (&host.api_url, &host.tokens));
+        std::iter::once((&self.api_url, &self.tokens))
+            .chain(hosts)
+            .filter_map(|(api_url, tokens)| {
+                let url = Url::parse(api_url).ok()?;
+                Some(TokenPool::new(url.host_str()?, tokens.clone()))
+            })
+            .collect()
+    }
+}
+
+/// The root of the GitHub API a request is for, picked by name with the
+/// `host` query parameter and otherwise the configured, or public, API, along
+/// with the host's GraphQL API if it's to be used.
+struct GithubApi<'r> {
+    url: &'r str,
+    graphql_url: Option<&'r str>,
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for GithubApi<'r> {
+    type Error = ScieldRequestError;
+
+    async fn from_request(request: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        let config = request.rocket().state::<GithubConfig>();
+        let graphql = config.is_some_and(|config| config.graphql);
+
+        match request.query_value::<&str>("host").and_then(Result::ok) {
+            None => request::Outcome::Success(match config {
+                Some(config) => GithubApi {
+                    url: &config.api_url,
+                    graphql_url: graphql.then_some(config.graphql_url.as_str()),
+                },
+                None => GithubApi {
+                    url: GITHUB_API_URL,
+                    graphql_url: None,
+                },
+            }),
+            Some(name) => match config.and_then(|config| config.hosts.get(name)) {
+                Some(host) => request::Outcome::Success(GithubApi {
+                    url: &host.api_url,
+                    graphql_url: host.graphql_url.as_deref().filter(|_| graphql),
+                }),
+                None => request::Outcome::Error((
+                    Status::BadRequest,
+                    ScieldRequestError::UnknownHost(name.to_string()),
+                )),
+            },
+        }
+    }
+}
 
 const WATCHERS_SCIELD: TextScield = TextScield {
     prefix: "Watchers",
@@ -13,7 +148,7 @@ const WATCHERS_SCIELD: TextScield = TextScield {
 };
 
 const FORKS_SCIELD: TextScield = TextScield {
-    prefix: "Watchers",
+    prefix: "Forks",
     suffix: None,
 };
 
@@ -62,6 +197,7 @@ impl FromStr for WorkflowState {
     }
 }
 
+#[allow(clippy::to_string_trait_impl)]
 impl ToString for WorkflowState {
     fn to_string(&self) -> String {
         match &self {
@@ -93,22 +229,153 @@ impl OpenState {
     }
 }
 
-#[derive(Debug)]
-enum OpenStateError {
-    InvalidOpenState,
-}
-
 impl<'r> FromParam<'r> for OpenState {
-    type Error = OpenStateError;
+    type Error = ScieldRequestError;
 
     fn from_param(param: &'r str) -> Result<Self, Self::Error> {
         match param {
             "all" => Ok(OpenState::All),
             "open" => Ok(OpenState::Open),
             "closed" => Ok(OpenState::Closed),
-            _ => Err(OpenStateError::InvalidOpenState),
+            _ => Err(ScieldRequestError::UnknownVariant(UnknownVariant {
+                kind: "state",
+                value: param.to_string(),
+                expected: &["all", "open", "closed"],
+            })),
+        }
+    }
+}
+
+fn repo_url(api: &GithubApi<'_>, owner: &str, repo: &str) -> String {
+    format!("{}/repos/{}/{}", api.url, owner, repo)
+}
+
+fn search_url(
+    api: &GithubApi<'_>,
+    owner: &str,
+    repo: &str,
+    kind: &str,
+    state: &OpenState,
+) -> String {
+    format!(
+        "{}/search/issues?q=repo:{}/{}+is:{}{}",
+        api.url,
+        owner,
+        repo,
+        kind,
+        state.to_search_param()
+    )
+}
+
+/// Fetches the payload for one of a repository's counts. With the host's
+/// GraphQL API, and a token to use it with, all of the repository's counts are
+/// fetched by a single query and cached under the REST URLs they'd otherwise
+/// be fetched from, falling back to the REST API if the query fails.
+async fn get_repo_payload(
+    upstream: &Upstream<'_>,
+    api: &GithubApi<'_>,
+    service: &str,
+    owner: &str,
+    repo: &str,
+    url: &str,
+) -> Result<Value, FetchError> {
+    let graphql_url = match api.graphql_url {
+        Some(graphql_url) if upstream.client.authenticates(graphql_url) => graphql_url,
+        _ => return get_payload(upstream, service, url).await,
+    };
+
+    let payloads = repo_payloads(api, owner, repo);
+    let key = format!("{}#{}/{}", graphql_url, owner, repo);
+    let (owner, repo) = (owner.to_string(), repo.to_string());
+    let fallback = (service.to_string(), url.to_string());
+    let query_url = graphql_url.to_string();
+    let fetcher = Fetcher::query(graphql_url, move |client, cache, in_flight| {
+        let query = query_repo(
+            client.clone(),
+            cache.clone(),
+            query_url.clone(),
+            payloads.clone(),
+            owner.clone(),
+            repo.clone(),
+        );
+        let (client, cache, in_flight) = (client.clone(), cache.clone(), in_flight.clone());
+        let (service, url) = fallback.clone();
+
+        in_flight
+            .start(&key, query)
+            .then(move |payloads| async move {
+                match payloads.map(|mut payloads| payloads[url.as_str()].take()) {
+                    Ok(payload) if !payload.is_null() => Ok(payload),
+                    _ => in_flight.fetch(&client, &cache, &service, &url).await,
+                }
+            })
+            .boxed()
+            .shared()
+    });
+    get_payload_with(upstream, service, url, fetcher).await
+}
+
+/// The REST URLs of a repository's counts, and the services reading them, in
+/// the order of the payloads made by `query_repo`.
+fn repo_payloads(api: &GithubApi<'_>, owner: &str, repo: &str) -> Vec<(&'static str, String)> {
+    let mut payloads = vec![("github_stars", repo_url(api, owner, repo))];
+    for (service, kind) in [("github_issues", "issue"), ("github_pull_requests", "pr")] {
+        for state in [OpenState::All, OpenState::Open, OpenState::Closed] {
+            payloads.push((service, search_url(api, owner, repo, kind, &state)));
         }
     }
+    payloads
+}
+
+/// Queries a repository's counts from the GraphQL API, caching each as the
+/// payload of its REST URL, and returns the payloads keyed by those URLs.
+async fn query_repo(
+    client: UpstreamClient,
+    cache: PayloadCache,
+    graphql_url: String,
+    urls: Vec<(&'static str, String)>,
+    owner: String,
+    repo: String,
+) -> Result<Value, FetchError> {
+    let query = json!({
+        "query": REPO_QUERY,
+        "variables": {"owner": owner, "name": repo},
+    });
+    let response = client.post(&graphql_url, &query).await?;
+    let repository = response
+        .pointer("/data/repository")
+        .filter(|repository| repository.is_object())
+        .ok_or(FetchError::Decode)?;
+
+    let count = |field: &str| {
+        repository
+            .pointer(field)
+            .and_then(Value::as_u64)
+            .ok_or(FetchError::Decode)
+    };
+    let total = |field| Ok::<_, FetchError>(json!({ "total_count": count(field)? }));
+    let counts = [
+        json!({
+            "subscribers_count": count("/watchers/totalCount")?,
+            "forks_count": count("/forkCount")?,
+            "stargazers_count": count("/stargazerCount")?,
+        }),
+        total("/issues/totalCount")?,
+        total("/openIssues/totalCount")?,
+        total("/closedIssues/totalCount")?,
+        total("/pullRequests/totalCount")?,
+        total("/openPullRequests/totalCount")?,
+        total("/closedPullRequests/totalCount")?,
+    ];
+
+    let mut payloads = Map::new();
+    for ((service, url), payload) in urls.into_iter().zip(counts) {
+        cache
+            .insert(service, &url, Ok(payload.clone()), Validators::default())
+            .await;
+        payloads.insert(url, payload);
+    }
+    Ok(Value::Object(payloads))
 }
 
 pub fn routes() -> Vec<rocket::Route> {
@@ -126,18 +393,29 @@ pub fn routes() -> Vec<rocket::Route> {
 
 #[get("/watchers/<owner>/<repo>")]
 async fn watchers(
-    client: &State<Client>,
+    upstream: Upstream<'_>,
+    api: Result<GithubApi<'_>, ScieldRequestError>,
     owner: &str,
-    repo: ScieldRequest<String>,
-) -> Option<Scield<f64, TextScield>> {
-    let request_url = format!("{}/repos/{}/{}", GITHUB_API_URL, owner, repo.body);
-
-    let watchers = get_payload(client, &request_url)
-        .await?
-        .get("subscribers_count")?
-        .as_f64()?;
-
-    Some(Scield {
+    repo: Result<ScieldRequest<String>, ScieldRequestError>,
+) -> Result<Scield<f64, TextScield>, ScieldError> {
+    let api = api?;
+    let repo = repo?;
+    let request_url = repo_url(&api, owner, &repo.body);
+
+    let watchers = get_repo_payload(
+        &upstream,
+        &api,
+        "github_watchers",
+        owner,
+        &repo.body,
+        &request_url,
+    )
+    .await?
+    .get("subscribers_count")
+    .and_then(Value::as_f64)
+    .ok_or(FetchError::Decode)?;
+
+    Ok(Scield {
         scield: WATCHERS_SCIELD,
         value: watchers,
         filetype: repo.filetype,
@@ -146,18 +424,29 @@ async fn watchers(
 
 #[get("/forks/<owner>/<repo>")]
 async fn forks(
-    client: &State<Client>,
+    upstream: Upstream<'_>,
+    api: Result<GithubApi<'_>, ScieldRequestError>,
     owner: &str,
-    repo: ScieldRequest<String>,
-) -> Option<Scield<f64, TextScield>> {
-    let request_url = format!("{}/repos/{}/{}", GITHUB_API_URL, owner, repo.body);
-
-    let forks = get_payload(client, &request_url)
-        .await?
-        .get("forks_count")?
-        .as_f64()?;
-
-    Some(Scield {
+    repo: Result<ScieldRequest<String>, ScieldRequestError>,
+) -> Result<Scield<f64, TextScield>, ScieldError> {
+    let api = api?;
+    let repo = repo?;
+    let request_url = repo_url(&api, owner, &repo.body);
+
+    let forks = get_repo_payload(
+        &upstream,
+        &api,
+        "github_forks",
+        owner,
+        &repo.body,
+        &request_url,
+    )
+    .await?
+    .get("forks_count")
+    .and_then(Value::as_f64)
+    .ok_or(FetchError::Decode)?;
+
+    Ok(Scield {
         scield: FORKS_SCIELD,
         value: forks,
         filetype: repo.filetype,
@@ -166,18 +455,29 @@ async fn forks(
 
 #[get("/stars/<owner>/<repo>")]
 async fn stars(
-    client: &State<Client>,
+    upstream: Upstream<'_>,
+    api: Result<GithubApi<'_>, ScieldRequestError>,
     owner: &str,
-    repo: ScieldRequest<String>,
-) -> Option<Scield<f64, TextScield>> {
-    let request_url = format!("{}/repos/{}/{}", GITHUB_API_URL, owner, repo.body);
-
-    let stars = get_payload(client, &request_url)
-        .await?
-        .get("stargazers_count")?
-        .as_f64()?;
-
-    Some(Scield {
+    repo: Result<ScieldRequest<String>, ScieldRequestError>,
+) -> Result<Scield<f64, TextScield>, ScieldError> {
+    let api = api?;
+    let repo = repo?;
+    let request_url = repo_url(&api, owner, &repo.body);
+
+    let stars = get_repo_payload(
+        &upstream,
+        &api,
+        "github_stars",
+        owner,
+        &repo.body,
+        &request_url,
+    )
+    .await?
+    .get("stargazers_count")
+    .and_then(Value::as_f64)
+    .ok_or(FetchError::Decode)?;
+
+    Ok(Scield {
         scield: STARS_SCIELD,
         value: stars,
         filetype: repo.filetype,
@@ -186,17 +486,21 @@ async fn stars(
 
 #[get("/followers/<user>")]
 async fn followers(
-    client: &State<Client>,
-    user: ScieldRequest<String>,
-) -> Option<Scield<f64, TextScield>> {
-    let request_url = format!("{}/users/{}", GITHUB_API_URL, user.body);
-
-    let followers = get_payload(client, &request_url)
+    upstream: Upstream<'_>,
+    api: Result<GithubApi<'_>, ScieldRequestError>,
+    user: Result<ScieldRequest<String>, ScieldRequestError>,
+) -> Result<Scield<f64, TextScield>, ScieldError> {
+    let api = api?;
+    let user = user?;
+    let request_url = format!("{}/users/{}", api.url, user.body);
+
+    let followers = get_payload(&upstream, "github_followers", &request_url)
         .await?
-        .get("followers")?
-        .as_f64()?;
+        .get("followers")
+        .and_then(Value::as_f64)
+        .ok_or(FetchError::Decode)?;
 
-    Some(Scield {
+    Ok(Scield {
         scield: FOLLOWERS_SCIELD,
         value: followers,
         filetype: user.filetype,
@@ -205,23 +509,24 @@ async fn followers(
 
 #[get("/latest_release/<owner>/<repo>")]
 async fn latest_release(
-    client: &State<Client>,
+    upstream: Upstream<'_>,
+    api: Result<GithubApi<'_>, ScieldRequestError>,
     owner: &str,
-    repo: ScieldRequest<String>,
-) -> Option<Scield<String, TextScield>> {
-    let request_url = format!(
-        "{}/repos/{}/{}/releases/latest",
-        GITHUB_API_URL, owner, repo.body
-    );
+    repo: Result<ScieldRequest<String>, ScieldRequestError>,
+) -> Result<Scield<String, TextScield>, ScieldError> {
+    let api = api?;
+    let repo = repo?;
+    let request_url = format!("{}/repos/{}/{}/releases/latest", api.url, owner, repo.body);
 
     let latest_release = String::from(
-        get_payload(client, &request_url)
+        get_payload(&upstream, "github_latest_release", &request_url)
             .await?
-            .get("tag_name")?
-            .as_str()?,
+            .get("tag_name")
+            .and_then(Value::as_str)
+            .ok_or(FetchError::Decode)?,
     );
 
-    Some(Scield {
+    Ok(Scield {
         scield: LATEST_RELEASE_SCIELD,
         value: latest_release,
         filetype: repo.filetype,
@@ -230,25 +535,31 @@ async fn latest_release(
 
 #[get("/issues/<state>/<owner>/<repo>")]
 async fn issues(
-    client: &State<Client>,
-    state: OpenState,
+    upstream: Upstream<'_>,
+    api: Result<GithubApi<'_>, ScieldRequestError>,
+    state: Result<OpenState, ScieldRequestError>,
     owner: &str,
-    repo: ScieldRequest<String>,
-) -> Option<Scield<f64, TextScield>> {
-    let request_url = format!(
-        "{}/search/issues?q=repo:{}/{}+is:issue{}",
-        GITHUB_API_URL,
+    repo: Result<ScieldRequest<String>, ScieldRequestError>,
+) -> Result<Scield<f64, TextScield>, ScieldError> {
+    let api = api?;
+    let state = state?;
+    let repo = repo?;
+    let request_url = search_url(&api, owner, &repo.body, "issue", &state);
+
+    let issues = get_repo_payload(
+        &upstream,
+        &api,
+        "github_issues",
         owner,
-        repo.body,
-        state.to_search_param()
-    );
-
-    let issues = get_payload(client, &request_url)
-        .await?
-        .get("total_count")?
-        .as_f64()?;
-
-    Some(Scield {
+        &repo.body,
+        &request_url,
+    )
+    .await?
+    .get("total_count")
+    .and_then(Value::as_f64)
+    .ok_or(FetchError::Decode)?;
+
+    Ok(Scield {
         scield: ISSUES_SCIELD,
         value: issues,
         filetype: repo.filetype,
@@ -257,61 +568,379 @@ async fn issues(
 
 #[get("/pull_requests/<state>/<owner>/<repo>")]
 async fn pull_requests(
-    client: &State<Client>,
-    state: OpenState,
+    upstream: Upstream<'_>,
+    api: Result<GithubApi<'_>, ScieldRequestError>,
+    state: Result<OpenState, ScieldRequestError>,
     owner: &str,
-    repo: ScieldRequest<String>,
-) -> Option<Scield<f64, TextScield>> {
-    let request_url = format!(
-        "{}/search/issues?q=repo:{}/{}+is:pr{}",
-        GITHUB_API_URL,
+    repo: Result<ScieldRequest<String>, ScieldRequestError>,
+) -> Result<Scield<f64, TextScield>, ScieldError> {
+    let api = api?;
+    let state = state?;
+    let repo = repo?;
+    let request_url = search_url(&api, owner, &repo.body, "pr", &state);
+
+    let pulls = get_repo_payload(
+        &upstream,
+        &api,
+        "github_pull_requests",
         owner,
-        repo.body,
-        state.to_search_param()
-    );
-
-    let pulls = get_payload(client, &request_url)
-        .await?
-        .get("total_count")?
-        .as_f64()?;
-
-    Some(Scield {
+        &repo.body,
+        &request_url,
+    )
+    .await?
+    .get("total_count")
+    .and_then(Value::as_f64)
+    .ok_or(FetchError::Decode)?;
+
+    Ok(Scield {
         scield: PULL_REQUESTS_SCIELD,
         value: pulls,
         filetype: repo.filetype,
     })
 }
 
-#[get("/workflow/<owner>/<repo>/<workflow>/<branch>")]
+#[get("/workflow/<owner>/<repo>/<workflow>/<branch..>")]
 async fn workflow(
-    client: &State<Client>,
+    upstream: Upstream<'_>,
+    api: Result<GithubApi<'_>, ScieldRequestError>,
     owner: &str,
     repo: &str,
     workflow: &str,
-    branch: ScieldRequest<String>,
-) -> Option<Scield<WorkflowState, StateScield>> {
+    branch: Result<ScieldRequest<String>, ScieldRequestError>,
+) -> Result<Scield<WorkflowState, StateScield>, ScieldError> {
+    let api = api?;
+    let branch = branch?;
     let request_url = format!(
         "{}/repos/{}/{}/actions/workflows/{}/runs?branch={}&per_page=1&status=completed",
-        GITHUB_API_URL, owner, repo, workflow, branch.body
+        api.url,
+        owner,
+        repo,
+        workflow,
+        RawStr::new(&branch.body).percent_encode()
     );
 
-    let payload = get_payload(client, &request_url).await?;
+    let payload = get_payload(&upstream, "github_workflow", &request_url).await?;
 
-    let status = if payload.get("total_count")?.as_i64()? == 0 {
-        String::from("unknown")
+    let total_count = payload
+        .get("total_count")
+        .and_then(Value::as_i64)
+        .ok_or(FetchError::Decode)?;
+
+    let status = if total_count == 0 {
+        "unknown"
     } else {
         payload
-            .pointer("/workflow_runs/0/conclusion")?
-            .as_str()?
-            .to_string()
+            .pointer("/workflow_runs/0/conclusion")
+            .and_then(Value::as_str)
+            .ok_or(FetchError::Decode)?
     };
 
-    match WorkflowState::from_str(&status) {
-        Ok(value) => Some(Scield {
+    match WorkflowState::from_str(status) {
+        Ok(value) => Ok(Scield {
             scield: WORKFLOW_SCIELD,
             value,
             filetype: branch.filetype,
         }),
-        Err(_) => None,
+        Err(_) => Err(ScieldError::NotFound),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cache::{CacheConfig, PayloadCache};
+    use crate::client::{ClientConfig, UpstreamClient};
+    use crate::utils::InFlight;
+    use crate::warming::{Warmer, WarmingConfig};
+    use rocket::local::asynchronous::Client;
+    use serde_json::json;
+    use wiremock::matchers::{method, path, query_param};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    async fn github(stars: u64) -> MockServer {
+        let server = MockServer::start().await;
+        Mock::given(path("/repos/autophagy/scieldas"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "stargazers_count": stars
+            })))
+            .mount(&server)
+            .await;
+        server
+    }
+
+    async fn client(config: GithubConfig) -> Client {
+        let client = UpstreamClient::new(ClientConfig::default(), config.token_pools()).unwrap();
+        let rocket = rocket::build()
+            .manage(client)
+            .manage(PayloadCache::new(CacheConfig::default()))
+            .manage(InFlight::default())
+            .manage(config)
+            .mount("/github", routes());
+        Client::tracked(rocket).await.unwrap()
+    }
+
+    /// Answers the query for autophagy/scieldas's counts.
+    async fn repo_query(server: &MockServer) {
+        Mock::given(method("POST"))
+            .and(path("/graphql"))
+            .and(wiremock::matchers::body_partial_json(json!({
+                "variables": {"owner": "autophagy", "name": "scieldas"}
+            })))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "data": {"repository": {
+                    "watchers": {"totalCount": 3},
+                    "forkCount": 4,
+                    "stargazerCount": 5,
+                    "issues": {"totalCount": 6},
+                    "openIssues": {"totalCount": 7},
+                    "closedIssues": {"totalCount": 8},
+                    "pullRequests": {"totalCount": 9},
+                    "openPullRequests": {"totalCount": 10},
+                    "closedPullRequests": {"totalCount": 11},
+                }}
+            })))
+            .mount(server)
+            .await;
+    }
+
+    #[rocket::async_test]
+    async fn test_hosts() {
+        let public = github(1).await;
+        let enterprise = github(2).await;
+        let config = GithubConfig {
+            api_url: public.uri(),
+            hosts: HashMap::from([(
+                "acme".to_string(),
+                GithubHost {
+                    api_url: enterprise.uri(),
+                    graphql_url: None,
+                    tokens: Vec::new(),
+                },
+            )]),
+            ..GithubConfig::default()
+        };
+        let client = client(config).await;
+
+        let response = client
+            .get("/github/stars/autophagy/scieldas.txt")
+            .dispatch();
+        assert_eq!(response.await.into_string().await.unwrap(), "Stars :: 1");
+
+        let response = client
+            .get("/github/stars/autophagy/scieldas.txt?host=acme")
+            .dispatch();
+        assert_eq!(response.await.into_string().await.unwrap(), "Stars :: 2");
+
+        let response = client
+            .get("/github/stars/autophagy/scieldas.json?host=initech")
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::BadRequest);
+        assert_eq!(
+            response.into_string().await.unwrap(),
+            r#"{"error":"unknown_host","expected":null,"message":"unknown host initech"}"#
+        );
+    }
+
+    #[rocket::async_test]
+    async fn test_unsupported_filetype() {
+        let server = github(1).await;
+        let client = client(GithubConfig {
+            api_url: server.uri(),
+            ..GithubConfig::default()
+        })
+        .await;
+
+        let response = client
+            .get("/github/stars/autophagy/scieldas.gif")
+            .header(rocket::http::Accept::JSON)
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::BadRequest);
+        let body = response.into_string().await.unwrap();
+        assert!(
+            body.contains(r#""error":"unsupported_filetype""#),
+            "{}",
+            body
+        );
+        assert!(server.received_requests().await.unwrap().is_empty());
+    }
+
+    #[rocket::async_test]
+    async fn test_graphql() {
+        let server = github(1).await;
+        repo_query(&server).await;
+        Mock::given(method("POST"))
+            .and(path("/graphql"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "data": {"repository": null},
+                "errors": [{"type": "NOT_FOUND"}]
+            })))
+            .mount(&server)
+            .await;
+        let client = client(GithubConfig {
+            api_url: server.uri(),
+            graphql_url: format!("{}/graphql", server.uri()),
+            tokens: vec!["token".to_string()],
+            ..GithubConfig::default()
+        })
+        .await;
+        let get = |uri: &'static str| client.get(uri).dispatch();
+
+        let response = get("/github/stars/autophagy/scieldas.txt").await;
+        assert_eq!(response.into_string().await.unwrap(), "Stars :: 5");
+        let response = get("/github/forks/autophagy/scieldas.txt").await;
+        assert_eq!(response.into_string().await.unwrap(), "Forks :: 4");
+        let response = get("/github/issues/open/autophagy/scieldas.txt").await;
+        assert_eq!(response.into_string().await.unwrap(), "Issues :: 7");
+        let response = get("/github/pull_requests/closed/autophagy/scieldas.txt").await;
+        assert_eq!(response.into_string().await.unwrap(), "Pull Requests :: 11");
+
+        // A single query answered all of the above.
+        let requests = server.received_requests().await.unwrap();
+        assert_eq!(requests.len(), 1);
+
+        // The query finds nothing, so the REST API is asked instead.
+        let response = get("/github/stars/autophagy/scieldas-rs.txt").await;
+        assert_eq!(response.status(), Status::NotFound);
+        // Clients may cache the error for as long as the failure is cached.
+        let max_age = response
+            .headers()
+            .get_one("Cache-Control")
+            .and_then(|header| header.strip_prefix("max-age="))
+            .and_then(|max_age| max_age.parse::<u64>().ok())
+            .unwrap();
+        assert!((290..=300).contains(&max_age));
+        let requests = server.received_requests().await.unwrap();
+        assert_eq!(requests.len(), 3);
+        assert_eq!(requests[2].url.path(), "/repos/autophagy/scieldas-rs");
+    }
+
+    #[rocket::async_test]
+    async fn test_graphql_warming() {
+        let server = github(1).await;
+        repo_query(&server).await;
+        let config = GithubConfig {
+            api_url: server.uri(),
+            graphql_url: format!("{}/graphql", server.uri()),
+            tokens: vec!["token".to_string()],
+            ..GithubConfig::default()
+        };
+        let warmer = Warmer::new(WarmingConfig {
+            lead: 3600,
+            ..WarmingConfig::default()
+        });
+        let rocket = rocket::build()
+            .manage(UpstreamClient::new(ClientConfig::default(), config.token_pools()).unwrap())
+            .manage(PayloadCache::new(CacheConfig::default()))
+            .manage(InFlight::default())
+            .manage(warmer.clone())
+            .manage(config)
+            .mount("/github", routes());
+        let client = Client::tracked(rocket).await.unwrap();
+
+        for uri in [
+            "/github/stars/autophagy/scieldas.txt",
+            "/github/issues/open/autophagy/scieldas.txt",
+        ] {
+            client.get(uri).dispatch().await;
+        }
+
+        // Popular counts are warmed by the query they were fetched with,
+        // rather than from the core and search REST APIs.
+        let rocket = client.rocket();
+        let warmed = warmer
+            .warm(
+                rocket.state().unwrap(),
+                rocket.state().unwrap(),
+                rocket.state().unwrap(),
+            )
+            .await;
+        assert_eq!(warmed, 2);
+        let requests = server.received_requests().await.unwrap();
+        assert_eq!(requests.len(), 3);
+        assert!(requests.iter().all(|r| r.url.path() == "/graphql"));
+    }
+
+    #[rocket::async_test]
+    async fn test_dotted_names() {
+        let server = MockServer::start().await;
+        Mock::given(path("/repos/socketio/socket.io"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "stargazers_count": 7
+            })))
+            .mount(&server)
+            .await;
+        Mock::given(path(
+            "/repos/autophagy/scieldas/actions/workflows/ci.yml/runs",
+        ))
+        .and(query_param("branch", "release/v1.2.3"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+            "total_count": 1,
+            "workflow_runs": [{"conclusion": "success"}]
+        })))
+        .mount(&server)
+        .await;
+        let client = client(GithubConfig {
+            api_url: server.uri(),
+            ..GithubConfig::default()
+        })
+        .await;
+
+        let response = client
+            .get("/github/stars/socketio/socket.io.txt")
+            .dispatch();
+        assert_eq!(response.await.into_string().await.unwrap(), "Stars :: 7");
+
+        let response = client
+            .get("/github/stars/socketio/socket.io")
+            .header(rocket::http::Accept::Text)
+            .dispatch();
+        assert_eq!(response.await.into_string().await.unwrap(), "Stars :: 7");
+
+        let response = client
+            .get("/github/workflow/autophagy/scieldas/ci.yml/release/v1.2.3.txt")
+            .dispatch();
+        assert_eq!(
+            response.await.into_string().await.unwrap(),
+            "Build :: Passing"
+        );
+    }
+
+    #[rocket::async_test]
+    async fn test_unexpected_payload() {
+        let server = MockServer::start().await;
+        Mock::given(path("/users/autophagy"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({})))
+            .mount(&server)
+            .await;
+        let client = client(GithubConfig {
+            api_url: server.uri(),
+            ..GithubConfig::default()
+        })
+        .await;
+
+        let response = client
+            .get("/github/followers/autophagy.json")
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::BadGateway);
+        assert_eq!(response.headers().get_one("Vary"), None);
+        assert_eq!(
+            response.into_string().await.unwrap(),
+            r#"{"error":"invalid_payload","expected":null,"message":"invalid upstream payload"}"#
+        );
+
+        // Errors negotiated from the Accept header vary by it, whether
+        // they're rendered as JSON or as a scield.
+        for accept in [rocket::http::Accept::JSON, rocket::http::Accept::Text] {
+            let response = client
+                .get("/github/followers/autophagy")
+                .header(accept)
+                .dispatch()
+                .await;
+            assert_eq!(response.status(), Status::BadGateway);
+            assert_eq!(response.headers().get_one("Vary"), Some("Accept"));
+        }
     }
 }
@@ -1,4 +1,6 @@
-use crate::scieldas::{Scield, ScieldRequest, StateScield};
+use crate::scieldas::{
+    Scield, ScieldError, ScieldRequest, ScieldRequestError, StateScield, UnknownVariant,
+};
 use std::str::FromStr;
 
 enum Licence {
@@ -7,21 +9,24 @@ enum Licence {
     Gpl,
 }
 
-struct ParseLicenceError;
-
 impl FromStr for Licence {
-    type Err = ParseLicenceError;
+    type Err = UnknownVariant;
 
-    fn from_str(s: &str) -> Result<Licence, ParseLicenceError> {
+    fn from_str(s: &str) -> Result<Licence, UnknownVariant> {
         match &s.to_lowercase()[..] {
             "mit" => Ok(Licence::Mit),
             "apache" => Ok(Licence::Apache),
             "gpl" => Ok(Licence::Gpl),
-            _ => Err(ParseLicenceError),
+            _ => Err(UnknownVariant {
+                kind: "licence",
+                value: s.to_string(),
+                expected: &["mit", "apache", "gpl"],
+            }),
         }
     }
 }
 
+#[allow(clippy::to_string_trait_impl)]
 impl ToString for Licence {
     fn to_string(&self) -> String {
         match &self {
@@ -42,10 +47,91 @@ pub fn routes() -> Vec<rocket::Route> {
 }
 
 #[get("/<license>")]
-async fn license(license: ScieldRequest<Licence>) -> Scield<Licence, StateScield> {
-    Scield {
+async fn license(
+    license: Result<ScieldRequest<Licence>, ScieldRequestError>,
+) -> Result<Scield<Licence, StateScield>, ScieldError> {
+    let license = license?;
+
+    Ok(Scield {
         scield: LICENCE_SCIELD,
         value: license.body,
         filetype: license.filetype,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use rocket::http::{ContentType, Header, Status};
+    use rocket::local::blocking::Client;
+
+    #[test]
+    fn test_license() {
+        let rocket = rocket::build().mount("/licenses", super::routes());
+        let client = Client::tracked(rocket).unwrap();
+
+        let response = client.get("/licenses/mit.txt").dispatch();
+        assert_eq!(response.content_type(), Some(ContentType::Plain));
+        assert_eq!(response.into_string().unwrap(), "MIT");
+
+        let response = client.get("/licenses/apache.svg").dispatch();
+        assert_eq!(response.content_type(), Some(ContentType::SVG));
+
+        let response = client.get("/licenses/gpl").dispatch();
+        assert_eq!(response.content_type(), Some(ContentType::SVG));
+
+        let response = client.get("/licenses/mit.txt?label=Licence").dispatch();
+        assert_eq!(response.into_string().unwrap(), "Licence :: MIT");
+
+        let response = client.get("/licenses/gpl.json").dispatch();
+        assert_eq!(
+            response.into_string().unwrap(),
+            r#"{"label":null,"message":"GPL 3","text":"GPL 3"}"#
+        );
+
+        let response = client.get("/licenses/mit.gif").dispatch();
+        assert_eq!(response.status(), Status::BadRequest);
+        assert_eq!(response.content_type(), Some(ContentType::SVG));
+        assert_eq!(
+            response.headers().get_one("Cache-Control"),
+            Some("no-store")
+        );
+
+        let response = client.get("/licenses/bsd.json").dispatch();
+        assert_eq!(response.status(), Status::BadRequest);
+        assert_eq!(
+            response.into_string().unwrap(),
+            r#"{"error":"unknown_variant","expected":["mit","apache","gpl"],"message":"unknown licence bsd, expected one of mit, apache, gpl"}"#
+        );
+    }
+
+    #[test]
+    fn test_license_caching() {
+        let rocket = rocket::build().mount("/licenses", super::routes());
+        let client = Client::tracked(rocket).unwrap();
+
+        let response = client.get("/licenses/mit.svg").dispatch();
+        assert_eq!(
+            response.headers().get_one("Cache-Control"),
+            Some("max-age=86400")
+        );
+        let etag = response.headers().get_one("ETag").unwrap().to_string();
+
+        let response = client
+            .get("/licenses/mit.svg")
+            .header(Header::new("If-None-Match", etag.clone()))
+            .dispatch();
+        assert_eq!(response.status(), Status::NotModified);
+        assert_eq!(response.headers().get_one("ETag"), Some(etag.as_str()));
+        assert!(response.into_bytes().unwrap_or_default().is_empty());
+
+        let response = client
+            .get("/licenses/gpl.svg?max_age=60")
+            .header(Header::new("If-None-Match", etag))
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(
+            response.headers().get_one("Cache-Control"),
+            Some("max-age=60")
+        );
     }
 }
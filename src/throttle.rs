@@ -0,0 +1,299 @@
+use cached::{Cached, SizedCache};
+use rocket::Request;
+use serde::Deserialize;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+/// How many clients' buckets are kept, after which the least recently seen
+/// are forgotten.
+const MAX_CLIENTS: usize = 10_000;
+
+/// Configuration for throttling clients, read from the `throttle` table of
+/// the configuration, e.g.
+///
+/// ```toml
+/// [default.throttle]
+/// enabled = true
+///
+/// [default.throttle.requests]
+/// per_client = { rate = 5, burst = 100 }
+/// global = { rate = 200, burst = 1000 }
+///
+/// [default.throttle.misses]
+/// per_client = { rate = 0.5, burst = 30 }
+/// global = { rate = 10, burst = 100 }
+/// ```
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct ThrottleConfig {
+    pub enabled: bool,
+    /// The budget for requests for scieldas backed by upstream payloads,
+    /// whether or not they're cached.
+    pub requests: BudgetConfig,
+    /// The budget for requests that need a payload fetched from upstream.
+    pub misses: BudgetConfig,
+}
+
+/// A budget for each client, by IP address, and for all clients together.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct BudgetConfig {
+    pub per_client: Budget,
+    pub global: Budget,
+}
+
+/// A token bucket, refilled at `rate` tokens a second up to `burst` tokens.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Budget {
+    pub rate: f64,
+    pub burst: f64,
+}
+
+impl Default for ThrottleConfig {
+    fn default() -> ThrottleConfig {
+        ThrottleConfig {
+            enabled: true,
+            requests: BudgetConfig {
+                per_client: Budget {
+                    rate: 5.0,
+                    burst: 100.0,
+                },
+                global: Budget {
+                    rate: 200.0,
+                    burst: 1000.0,
+                },
+            },
+            misses: BudgetConfig {
+                per_client: Budget {
+                    rate: 0.5,
+                    burst: 30.0,
+                },
+                global: Budget {
+                    rate: 10.0,
+                    burst: 100.0,
+                },
+            },
+        }
+    }
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    fn full(budget: Budget) -> Bucket {
+        Bucket {
+            tokens: budget.burst,
+            updated: Instant::now(),
+        }
+    }
+
+    fn take(&mut self, budget: Budget) -> bool {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * budget.rate).min(budget.burst);
+        self.updated = now;
+
+        if self.tokens < 1.0 {
+            return false;
+        }
+        self.tokens -= 1.0;
+        true
+    }
+}
+
+/// The buckets for a budget. Clients whose address isn't known share a bucket.
+struct Buckets {
+    config: BudgetConfig,
+    global: Mutex<Bucket>,
+    clients: Mutex<SizedCache<Option<IpAddr>, Bucket>>,
+}
+
+impl Buckets {
+    fn new(config: BudgetConfig) -> Buckets {
+        Buckets {
+            global: Mutex::new(Bucket::full(config.global)),
+            clients: Mutex::new(SizedCache::with_size(MAX_CLIENTS)),
+            config,
+        }
+    }
+
+    fn take(&self, client: Option<IpAddr>) -> bool {
+        let budget = self.config.per_client;
+        let mut clients = self.clients.lock().unwrap();
+        let bucket = clients.cache_get_or_set_with(client, || Bucket::full(budget));
+        bucket.take(budget) && self.global.lock().unwrap().take(self.config.global)
+    }
+}
+
+/// Whether a request was let through by the throttle.
+struct Admitted(bool);
+
+/// Throttles clients with token buckets, per client and globally, with
+/// separate budgets for requests and for the upstream fetches they cause.
+///
+/// Requests are charged against the request budget by the `Upstream` guard, so
+/// scieldas that don't need upstream payloads, health checks and metrics
+/// scrapes neither spend from it nor are throttled.
+///
+/// The throttle is cheap to clone, with clones sharing the same buckets.
+#[derive(Clone)]
+pub struct Throttle {
+    enabled: bool,
+    requests: Arc<Buckets>,
+    misses: Arc<Buckets>,
+}
+
+impl Throttle {
+    pub fn new(config: ThrottleConfig) -> Throttle {
+        Throttle {
+            enabled: config.enabled,
+            requests: Arc::new(Buckets::new(config.requests)),
+            misses: Arc::new(Buckets::new(config.misses)),
+        }
+    }
+
+    /// Charges a request to its client the first time it's asked about,
+    /// returning whether it's within the request budget for them.
+    pub fn admit(&self, request: &Request<'_>) -> bool {
+        request
+            .local_cache(|| Admitted(!self.enabled || self.requests.take(request.client_ip())))
+            .0
+    }
+
+    /// Charges an upstream fetch to a client, returning whether it's within
+    /// the budget for them.
+    pub fn allow_miss(&self, client: Option<IpAddr>) -> bool {
+        !self.enabled || self.misses.take(client)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cache::{CacheConfig, PayloadCache};
+    use crate::client::{ClientConfig, UpstreamClient};
+    use crate::services::github::GithubConfig;
+    use crate::services::{github, licenses};
+    use crate::utils::InFlight;
+    use rocket::http::Status;
+    use rocket::local::asynchronous::Client;
+    use serde_json::json;
+    use std::time::Duration;
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    #[test]
+    fn test_bucket() {
+        let budget = Budget {
+            rate: 10.0,
+            burst: 2.0,
+        };
+        let mut bucket = Bucket::full(budget);
+        assert!(bucket.take(budget));
+        assert!(bucket.take(budget));
+        assert!(!bucket.take(budget));
+
+        bucket.updated -= Duration::from_millis(150);
+        assert!(bucket.take(budget));
+        assert!(!bucket.take(budget));
+
+        bucket.updated -= Duration::from_secs(60);
+        assert!(bucket.take(budget));
+        assert!(bucket.take(budget));
+        assert!(!bucket.take(budget));
+    }
+
+    #[test]
+    fn test_budgets() {
+        let buckets = Buckets::new(BudgetConfig {
+            per_client: Budget {
+                rate: 0.0,
+                burst: 2.0,
+            },
+            global: Budget {
+                rate: 0.0,
+                burst: 3.0,
+            },
+        });
+        let a = Some(IpAddr::from([127, 0, 0, 1]));
+        let b = Some(IpAddr::from([127, 0, 0, 2]));
+
+        assert!(buckets.take(a));
+        assert!(buckets.take(a));
+        assert!(!buckets.take(a));
+        assert!(buckets.take(b));
+        assert!(!buckets.take(None));
+    }
+
+    #[rocket::async_test]
+    async fn test_throttles_requests() {
+        let server = MockServer::start().await;
+        Mock::given(wiremock::matchers::any())
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "stargazers_count": 1
+            })))
+            .mount(&server)
+            .await;
+
+        let budget = |burst| Budget { rate: 0.0, burst };
+        let throttle = Throttle::new(ThrottleConfig {
+            enabled: true,
+            requests: BudgetConfig {
+                per_client: budget(2.0),
+                global: budget(100.0),
+            },
+            misses: BudgetConfig {
+                per_client: budget(1.0),
+                global: budget(100.0),
+            },
+        });
+        let config = GithubConfig {
+            api_url: server.uri(),
+            ..GithubConfig::default()
+        };
+        let rocket = rocket::build()
+            .manage(UpstreamClient::new(ClientConfig::default(), vec![]).unwrap())
+            .manage(PayloadCache::new(CacheConfig::default()))
+            .manage(InFlight::default())
+            .manage(config)
+            .manage(throttle)
+            .register("/", catchers![crate::too_many_requests])
+            .mount("/github", github::routes())
+            .mount("/licenses", licenses::routes());
+        let client = Client::tracked(rocket).await.unwrap();
+        let get = |uri: &'static str| client.get(uri).dispatch();
+
+        // Scieldas that don't need upstream payloads don't spend the budget.
+        for _ in 0..3 {
+            let response = get("/licenses/mit.txt").await;
+            assert_eq!(response.status(), Status::Ok);
+        }
+
+        let response = get("/github/stars/autophagy/a.txt").await;
+        assert_eq!(response.into_string().await.unwrap(), "Stars :: 1");
+
+        // Within the request budget, but over the budget for upstream fetches.
+        let response = get("/github/stars/autophagy/b.json").await;
+        assert_eq!(response.status(), Status::TooManyRequests);
+        let body = response.into_string().await.unwrap();
+        assert!(body.contains(r#""error":"too_many_requests""#));
+
+        // Over the request budget, even though the payload is cached. Clients
+        // mustn't hold on to the error once they're back within budget.
+        let response = get("/github/stars/autophagy/a.json").await;
+        assert_eq!(response.status(), Status::TooManyRequests);
+        assert_eq!(
+            response.headers().get_one("Cache-Control"),
+            Some("no-store")
+        );
+        let body = response.into_string().await.unwrap();
+        assert!(body.contains(r#""error":"too_many_requests""#));
+
+        let response = get("/licenses/mit.txt").await;
+        assert_eq!(response.status(), Status::Ok);
+    }
+}
@@ -0,0 +1,75 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// A pool of API tokens for a single host, handed out round-robin to requests
+/// for URLs on that host. Tokens the host rejects are dropped from the pool.
+///
+/// The pool is cheap to clone, with clones sharing the same tokens.
+#[derive(Clone)]
+pub struct TokenPool {
+    host: String,
+    tokens: Arc<Mutex<Vec<String>>>,
+    next: Arc<AtomicUsize>,
+}
+
+impl TokenPool {
+    pub fn new(host: &str, tokens: Vec<String>) -> TokenPool {
+        TokenPool {
+            host: host.to_string(),
+            tokens: Arc::new(Mutex::new(tokens)),
+            next: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    pub fn host(&self) -> &str {
+        &self.host
+    }
+
+    pub fn tokens(&self) -> Vec<String> {
+        self.tokens.lock().unwrap().clone()
+    }
+
+    /// The next token accepted by `usable`, if any.
+    pub fn next(&self, usable: impl Fn(&str) -> bool) -> Option<String> {
+        let tokens = self.tokens.lock().unwrap();
+        let next = self.next.fetch_add(1, Ordering::Relaxed);
+        (0..tokens.len())
+            .map(|i| &tokens[(next + i) % tokens.len()])
+            .find(|token| usable(token))
+            .cloned()
+    }
+
+    /// Drops a token the host has rejected.
+    pub fn revoke(&self, token: &str) {
+        let mut tokens = self.tokens.lock().unwrap();
+        tokens.retain(|t| t != token);
+        log::warn!(
+            "Dropped a token rejected by {}, {} left",
+            self.host,
+            tokens.len()
+        );
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_next() {
+        let pool = TokenPool::new("api.github.com", vec!["a".to_string(), "b".to_string()]);
+
+        assert_eq!(pool.next(|_| true), Some("a".to_string()));
+        assert_eq!(pool.next(|_| true), Some("b".to_string()));
+        assert_eq!(pool.next(|_| true), Some("a".to_string()));
+        assert_eq!(pool.next(|token| token != "a"), Some("b".to_string()));
+        assert_eq!(pool.next(|_| false), None);
+
+        pool.revoke("a");
+        assert_eq!(pool.next(|_| true), Some("b".to_string()));
+        assert_eq!(pool.next(|_| true), Some("b".to_string()));
+
+        pool.revoke("b");
+        assert_eq!(pool.next(|_| true), None);
+    }
+}
@@ -1,25 +1,604 @@
-use cached::proc_macro::cached;
-use cached::TimedSizedCache;
-use reqwest::Client;
-use rocket::State;
+use crate::cache::{Lookup, PayloadCache};
+use crate::client::{Fetched, UpstreamClient, Validators};
+use crate::logging::RequestId;
+use crate::metrics::CACHE_LOOKUPS;
+use crate::throttle::Throttle;
+use crate::warming::Warmer;
+use futures::future::{BoxFuture, FutureExt, Shared};
+use rocket::http::Status;
+use rocket::request::{self, FromRequest, Request};
+use serde::{Deserialize, Serialize};
 use serde_json::Value;
+use std::collections::HashMap;
+use std::future::Future;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
 
-#[cached(
-    type = "TimedSizedCache<String, Option<Value>>",
-    create = "{ TimedSizedCache::with_size_and_lifespan(1000, 300) }",
-    convert = "{ url.to_string() }"
-)]
-pub async fn get_payload(client: &State<Client>, url: &str) -> Option<Value> {
-    let response = client.get(url).send().await;
-
-    if let Ok(r) = response {
-        let root: Result<Value, reqwest::Error> = r.json().await;
-        if let Ok(s) = root {
-            Some(s)
+/// Request guard bundling the managed state needed to fetch upstream payloads.
+pub struct Upstream<'r> {
+    pub client: &'r UpstreamClient,
+    pub cache: &'r PayloadCache,
+    pub in_flight: &'r InFlight,
+    pub freshness: &'r Freshness,
+    pub throttle: Option<&'r Throttle>,
+    pub client_ip: Option<IpAddr>,
+    pub warmer: Option<&'r Warmer>,
+    pub request_id: Option<RequestId>,
+}
+
+impl Upstream<'_> {
+    /// Whether the client may cause another upstream fetch.
+    fn allow_miss(&self) -> bool {
+        match self.throttle {
+            Some(throttle) => throttle.allow_miss(self.client_ip),
+            None => true,
+        }
+    }
+}
+
+/// How fresh the upstream payloads used to answer a request are, so that the
+/// response can tell clients how long to cache it for.
+#[derive(Default)]
+pub struct Freshness {
+    // When the first of the payloads expires, and when the newest was fetched.
+    bounds: Mutex<Option<(SystemTime, SystemTime)>>,
+}
+
+impl Freshness {
+    pub fn of<'r>(request: &'r Request<'_>) -> &'r Freshness {
+        request.local_cache(Freshness::default)
+    }
+
+    fn record(&self, fetched_at: SystemTime, ttl: Duration) {
+        let expires = fetched_at + ttl;
+        let mut bounds = self.bounds.lock().unwrap();
+        *bounds = Some(match *bounds {
+            Some((e, f)) => (e.min(expires), f.max(fetched_at)),
+            None => (expires, fetched_at),
+        });
+    }
+
+    /// How long until the first of the payloads expires, or `None` if the
+    /// request didn't use any.
+    pub fn max_age(&self) -> Option<Duration> {
+        let (expires, _) = (*self.bounds.lock().unwrap())?;
+        Some(
+            expires
+                .duration_since(SystemTime::now())
+                .unwrap_or_default(),
+        )
+    }
+
+    pub fn last_modified(&self) -> Option<SystemTime> {
+        let (_, fetched_at) = (*self.bounds.lock().unwrap())?;
+        Some(fetched_at)
+    }
+}
+
+/// Why a payload couldn't be fetched from upstream, which decides how long the
+/// failure is cached for.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub enum FetchError {
+    /// The upstream doesn't know the requested resource.
+    NotFound,
+    /// The upstream won't let us see the requested resource.
+    Forbidden,
+    /// The upstream refused the request because we've hit its rate limit.
+    RateLimited,
+    /// The upstream failed, or answered with a status we don't expect.
+    ServerError,
+    /// The upstream's payload isn't JSON, or lacks what we need from it.
+    Decode,
+    /// Network errors and timeouts, which are likely to be resolved by trying
+    /// again shortly.
+    Transient,
+    /// The payload wasn't fetched, as the client asking for it has used up
+    /// its budget of upstream fetches. This is never cached.
+    Throttled,
+}
+
+pub type PendingFetch = Shared<BoxFuture<'static, Result<Value, FetchError>>>;
+
+type StartFetch = dyn Fn(&UpstreamClient, &PayloadCache, &InFlight) -> PendingFetch + Send + Sync;
+
+/// How a payload is fetched into the cache, kept with what's known of the
+/// payload so that it can be fetched again the same way outside of a request,
+/// such as to warm the cache. Payloads may be fetched from elsewhere than
+/// their URL, such as by a GraphQL query filling the cache for several URLs.
+#[derive(Clone)]
+pub struct Fetcher {
+    /// Where the fetch is made, and whether it's a query, which decide which
+    /// rate limit it spends from.
+    url: String,
+    query: bool,
+    start: Arc<StartFetch>,
+}
+
+impl Fetcher {
+    /// Fetches the payload of a URL from the URL itself.
+    pub fn get(service: &str, url: &str) -> Fetcher {
+        let (service, url) = (service.to_string(), url.to_string());
+        Fetcher {
+            url: url.clone(),
+            query: false,
+            start: Arc::new(
+                move |client: &UpstreamClient, cache: &PayloadCache, in_flight: &InFlight| {
+                    in_flight.fetch(client, cache, &service, &url)
+                },
+            ),
+        }
+    }
+
+    /// Fetches a payload with a query posted to a URL, started by `start`.
+    pub fn query<F>(url: &str, start: F) -> Fetcher
+    where
+        F: Fn(&UpstreamClient, &PayloadCache, &InFlight) -> PendingFetch + Send + Sync + 'static,
+    {
+        Fetcher {
+            url: url.to_string(),
+            query: true,
+            start: Arc::new(start),
+        }
+    }
+
+    /// Returns the pending fetch, starting one if there isn't already one
+    /// under way.
+    pub fn start(
+        &self,
+        client: &UpstreamClient,
+        cache: &PayloadCache,
+        in_flight: &InFlight,
+    ) -> PendingFetch {
+        (self.start)(client, cache, in_flight)
+    }
+
+    /// How many more requests the fetch's host allows, as
+    /// `UpstreamClient::headroom` reports.
+    pub fn headroom(&self, client: &UpstreamClient) -> Option<u64> {
+        if self.query {
+            client.query_headroom(&self.url)
         } else {
-            None
+            client.headroom(&self.url)
+        }
+    }
+}
+
+/// The upstream fetches currently under way, keyed by URL, so that concurrent
+/// requests for the same URL share a single upstream call. Fetches that fill
+/// the cache for several URLs at once are keyed by what they fetch instead.
+#[derive(Clone, Default)]
+pub struct InFlight {
+    fetches: Arc<Mutex<HashMap<String, PendingFetch>>>,
+}
+
+impl InFlight {
+    /// Returns the pending fetch of a URL, starting one if there isn't already
+    /// one under way. The fetch runs as its own task and stores its payload in
+    /// the cache, so it completes even if nothing awaits it.
+    pub fn fetch(
+        &self,
+        client: &UpstreamClient,
+        cache: &PayloadCache,
+        service: &str,
+        url: &str,
+    ) -> PendingFetch {
+        let client = client.clone();
+        let cache = cache.clone();
+        let service = service.to_string();
+        let url = url.to_string();
+
+        self.start(&url.clone(), async move {
+            refresh(&client, &cache, &service, &url).await
+        })
+    }
+
+    /// Returns the pending fetch under a key, starting `task` as its own task
+    /// if there isn't already one under way. The task is logged as working on
+    /// the request that started it.
+    pub fn start<F>(&self, key: &str, task: F) -> PendingFetch
+    where
+        F: Future<Output = Result<Value, FetchError>> + Send + 'static,
+    {
+        let mut fetches = self.fetches.lock().unwrap();
+
+        if let Some(fetch) = fetches.get(key) {
+            return fetch.clone();
+        }
+
+        let in_flight = self.clone();
+        let key = key.to_string();
+        let request_id = RequestId::current();
+
+        let task = rocket::tokio::spawn({
+            let key = key.clone();
+            async move {
+                let payload = RequestId::scope(request_id, task).await;
+                in_flight.fetches.lock().unwrap().remove(&key);
+                payload
+            }
+        });
+
+        let fetch = task
+            .map(|payload| payload.unwrap_or(Err(FetchError::Transient)))
+            .boxed()
+            .shared();
+        fetches.insert(key, fetch.clone());
+        fetch
+    }
+}
+
+/// Fetches the payload at a URL into the cache. If a payload from it is
+/// already cached, the upstream is asked whether it's changed, and if not the
+/// cached payload is kept and counted as freshly fetched.
+async fn refresh(
+    client: &UpstreamClient,
+    cache: &PayloadCache,
+    service: &str,
+    url: &str,
+) -> Result<Value, FetchError> {
+    let last_good = cache.last_good(url);
+    let validators = match &last_good {
+        Some(entry) => entry.validators.clone(),
+        None => Validators::default(),
+    };
+
+    let (payload, validators) = match (client.get(url, &validators).await, last_good) {
+        (Ok(Fetched::Modified(payload, validators)), _) => (Ok(payload), validators),
+        (Ok(Fetched::NotModified), Some(entry)) => {
+            log::debug!(url; "Cached payload of {} is unchanged", url);
+            (entry.payload, entry.validators)
+        }
+        // Nothing was cached to be unchanged from.
+        (Ok(Fetched::NotModified), None) => (Err(FetchError::ServerError), validators),
+        (Err(e), _) => (Err(e), validators),
+    };
+
+    cache
+        .insert(service, url, payload.clone(), validators)
+        .await;
+    payload
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Upstream<'r> {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        let rocket = request.rocket();
+        let throttle = rocket.state::<Throttle>();
+        if !throttle.is_none_or(|throttle| throttle.admit(request)) {
+            return request::Outcome::Error((Status::TooManyRequests, ()));
+        }
+
+        match (
+            rocket.state::<UpstreamClient>(),
+            rocket.state::<PayloadCache>(),
+            rocket.state::<InFlight>(),
+        ) {
+            (Some(client), Some(cache), Some(in_flight)) => request::Outcome::Success(Upstream {
+                client,
+                cache,
+                in_flight,
+                freshness: Freshness::of(request),
+                throttle,
+                client_ip: request.client_ip(),
+                warmer: rocket.state::<Warmer>(),
+                request_id: Some(RequestId::of(request).clone()),
+            }),
+            _ => request::Outcome::Error((Status::InternalServerError, ())),
         }
-    } else {
-        None
+    }
+}
+
+/// Fetches the JSON payload at the given URL, going through the payload cache
+/// using the TTL configured for `service`. Stale payloads are served straight
+/// away while they're refreshed in the background, and concurrent misses for
+/// the same URL wait on a single upstream fetch.
+pub async fn get_payload(
+    upstream: &Upstream<'_>,
+    service: &str,
+    url: &str,
+) -> Result<Value, FetchError> {
+    get_payload_with(upstream, service, url, Fetcher::get(service, url)).await
+}
+
+/// Like `get_payload`, but fetching the payload with `fetcher`, on a miss or
+/// to refresh a stale payload. The fetch must store the payload in the cache,
+/// as `InFlight::fetch` does.
+pub async fn get_payload_with(
+    upstream: &Upstream<'_>,
+    service: &str,
+    url: &str,
+    fetcher: Fetcher,
+) -> Result<Value, FetchError> {
+    let payload = get_payload_scoped(upstream, service, url, fetcher);
+    RequestId::scope(upstream.request_id.clone(), payload).await
+}
+
+async fn get_payload_scoped(
+    upstream: &Upstream<'_>,
+    service: &str,
+    url: &str,
+    fetcher: Fetcher,
+) -> Result<Value, FetchError> {
+    let fetch = || fetcher.start(upstream.client, upstream.cache, upstream.in_flight);
+    if let Some(warmer) = upstream.warmer {
+        warmer.record(service, url, &fetcher);
+    }
+
+    let lookup = upstream.cache.get(service, url).await;
+    let outcome = match lookup {
+        Lookup::Fresh(_) => "fresh",
+        Lookup::Stale(_) => "stale",
+        Lookup::Miss => "miss",
+    };
+    CACHE_LOOKUPS.with_label_values(&[outcome]).inc();
+    log::debug!(url, outcome; "Cache lookup of {} was {}", url, outcome);
+
+    let (payload, fetched_at) = match lookup {
+        Lookup::Fresh(entry) => (entry.payload, entry.fetched_at),
+        Lookup::Stale(entry) => {
+            // The refresh runs as its own task, so it doesn't need awaiting.
+            if entry.refreshable() && upstream.allow_miss() {
+                drop(fetch());
+            }
+            (entry.payload, entry.fetched_at)
+        }
+        Lookup::Miss if !upstream.allow_miss() => {
+            log::info!(url; "Not fetching {}, as the client is over its budget", url);
+            return Err(FetchError::Throttled);
+        }
+        Lookup::Miss => {
+            let payload = fetch().await;
+            (payload, SystemTime::now())
+        }
+    };
+
+    let ttl = match &payload {
+        Ok(_) => upstream.cache.ttl(service),
+        Err(e) => upstream.cache.negative_ttl(*e),
+    };
+    upstream.freshness.record(fetched_at, ttl);
+
+    payload
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cache::CacheConfig;
+    use crate::client::ClientConfig;
+    use serde_json::json;
+    use std::time::Duration;
+    use wiremock::matchers::{header, method};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    // Nothing listens on port 1, so fetches from it fail straight away.
+    const UNREACHABLE_URL: &str = "http://127.0.0.1:1/";
+
+    struct State {
+        client: UpstreamClient,
+        cache: PayloadCache,
+        in_flight: InFlight,
+        freshness: Freshness,
+    }
+
+    impl State {
+        fn new(config: CacheConfig) -> State {
+            // Retries are covered by the client's own tests.
+            let client_config = ClientConfig {
+                retries: 0,
+                ..ClientConfig::default()
+            };
+            State {
+                client: UpstreamClient::new(client_config, vec![]).unwrap(),
+                cache: PayloadCache::new(config),
+                in_flight: InFlight::default(),
+                freshness: Freshness::default(),
+            }
+        }
+
+        fn upstream(&self) -> Upstream<'_> {
+            Upstream {
+                client: &self.client,
+                cache: &self.cache,
+                in_flight: &self.in_flight,
+                freshness: &self.freshness,
+                throttle: None,
+                client_ip: None,
+                warmer: None,
+                request_id: None,
+            }
+        }
+    }
+
+    #[rocket::async_test]
+    async fn test_serves_stale_on_upstream_error() {
+        let state = State::new(CacheConfig {
+            ttl: 0,
+            ..CacheConfig::default()
+        });
+        let upstream = state.upstream();
+
+        assert_eq!(
+            get_payload(&upstream, "test", UNREACHABLE_URL).await,
+            Err(FetchError::Transient)
+        );
+
+        state
+            .cache
+            .insert("test", UNREACHABLE_URL, Ok(json!(1)), Validators::default())
+            .await;
+        assert_eq!(
+            get_payload(&upstream, "test", UNREACHABLE_URL).await,
+            Ok(json!(1))
+        );
+
+        // Let the background refresh fail, after which the stale payload
+        // should still be served.
+        let refresh = state
+            .in_flight
+            .fetch(&state.client, &state.cache, "test", UNREACHABLE_URL);
+        assert_eq!(refresh.await, Err(FetchError::Transient));
+        assert_eq!(
+            get_payload(&upstream, "test", UNREACHABLE_URL).await,
+            Ok(json!(1))
+        );
+    }
+
+    #[rocket::async_test]
+    async fn test_backs_off_failed_refreshes() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(500))
+            .mount(&server)
+            .await;
+
+        let state = State::new(CacheConfig {
+            ttl: 0,
+            ..CacheConfig::default()
+        });
+        let upstream = state.upstream();
+        let url = format!("{}/repos/autophagy/scieldas", server.uri());
+        state
+            .cache
+            .insert("test", &url, Ok(json!(1)), Validators::default())
+            .await;
+
+        // Only the first stale read refreshes the payload. Once that fails,
+        // the stale payload is served without refreshing it until the
+        // failure's negative TTL has passed.
+        for _ in 0..5 {
+            assert_eq!(get_payload(&upstream, "test", &url).await, Ok(json!(1)));
+            rocket::tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        assert_eq!(server.received_requests().await.unwrap().len(), 1);
+        assert!(state.cache.expires_in("test", &url).unwrap() > Duration::from_secs(10));
+    }
+
+    #[rocket::async_test]
+    async fn test_coalesces_concurrent_fetches() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .set_body_json(json!({"stargazers_count": 1}))
+                    .set_delay(Duration::from_millis(200)),
+            )
+            .mount(&server)
+            .await;
+
+        let state = State::new(CacheConfig::default());
+        let upstream = state.upstream();
+        let url = format!("{}/repos/autophagy/scieldas", server.uri());
+
+        let payloads = futures::future::join_all(
+            (0..50).map(|_| get_payload(&upstream, "github_stars", &url)),
+        )
+        .await;
+
+        assert!(payloads
+            .iter()
+            .all(|p| p == &Ok(json!({"stargazers_count": 1}))));
+        assert_eq!(server.received_requests().await.unwrap().len(), 1);
+    }
+
+    #[rocket::async_test]
+    async fn test_classifies_failures() {
+        let server = MockServer::start().await;
+        let responses = [
+            ("/missing", ResponseTemplate::new(404)),
+            ("/forbidden", ResponseTemplate::new(403)),
+            ("/broken", ResponseTemplate::new(500)),
+            (
+                "/html",
+                ResponseTemplate::new(200).set_body_string("<html>"),
+            ),
+            ("/limited", ResponseTemplate::new(429)),
+        ];
+        for (path, response) in responses {
+            Mock::given(method("GET"))
+                .and(wiremock::matchers::path(path))
+                .respond_with(response)
+                .mount(&server)
+                .await;
+        }
+
+        let state = State::new(CacheConfig::default());
+        let upstream = &state.upstream();
+        let server = &server;
+        let get = |path: &str| {
+            let url = format!("{}{}", server.uri(), path);
+            async move { get_payload(upstream, "test", &url).await }
+        };
+
+        assert_eq!(get("/missing").await, Err(FetchError::NotFound));
+        assert_eq!(get("/forbidden").await, Err(FetchError::Forbidden));
+        assert_eq!(get("/broken").await, Err(FetchError::ServerError));
+        assert_eq!(get("/html").await, Err(FetchError::Decode));
+        assert_eq!(get("/limited").await, Err(FetchError::RateLimited));
+
+        // Each failure is cached, so asking again doesn't hit the upstream.
+        assert_eq!(get("/missing").await, Err(FetchError::NotFound));
+        assert_eq!(server.received_requests().await.unwrap().len(), 5);
+    }
+
+    #[test]
+    fn test_freshness() {
+        let freshness = Freshness::default();
+        assert_eq!(freshness.max_age(), None);
+
+        let now = SystemTime::now();
+        let earlier = now - Duration::from_secs(100);
+        freshness.record(earlier, Duration::from_secs(300));
+        freshness.record(now, Duration::from_secs(60));
+
+        assert!(freshness.max_age().unwrap() <= Duration::from_secs(60));
+        assert!(freshness.max_age().unwrap() > Duration::from_secs(50));
+        assert_eq!(freshness.last_modified(), Some(now));
+
+        freshness.record(earlier, Duration::from_secs(10));
+        assert_eq!(freshness.max_age(), Some(Duration::ZERO));
+    }
+
+    #[rocket::async_test]
+    async fn test_revalidates() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(header("If-None-Match", "\"abc\""))
+            .respond_with(ResponseTemplate::new(304))
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .set_body_json(json!({"stargazers_count": 1}))
+                    .insert_header("ETag", "\"abc\""),
+            )
+            .mount(&server)
+            .await;
+
+        let state = State::new(CacheConfig {
+            ttl: 0,
+            ..CacheConfig::default()
+        });
+        let upstream = state.upstream();
+        let url = format!("{}/repos/autophagy/scieldas", server.uri());
+
+        let payload = get_payload(&upstream, "github_stars", &url).await;
+        assert_eq!(payload, Ok(json!({"stargazers_count": 1})));
+        let fetched_at = state.cache.last_good(&url).unwrap().fetched_at;
+
+        let refresh = state
+            .in_flight
+            .fetch(&state.client, &state.cache, "github_stars", &url);
+        assert_eq!(refresh.await, payload);
+
+        let entry = state.cache.last_good(&url).unwrap();
+        assert_eq!(entry.payload, payload);
+        assert_eq!(entry.validators.etag.as_deref(), Some("\"abc\""));
+        assert!(entry.fetched_at > fetched_at);
+        assert_eq!(server.received_requests().await.unwrap().len(), 2);
     }
 }
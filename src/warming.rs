@@ -0,0 +1,301 @@
+use crate::cache::PayloadCache;
+use crate::client::UpstreamClient;
+use crate::utils::{Fetcher, InFlight};
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::tokio::time;
+use rocket::Rocket;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Configuration for warming the cache, read from the `warming` table of
+/// the configuration, e.g.
+///
+/// ```toml
+/// [default.warming]
+/// enabled = true
+/// interval = 30
+/// popular = 100
+/// lead = 60
+/// reserve = 500
+/// ```
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct WarmingConfig {
+    pub enabled: bool,
+    /// How often, in seconds, popular payloads are checked.
+    pub interval: u64,
+    /// How many of the most requested payloads are kept warm.
+    pub popular: usize,
+    /// How long, in seconds, before a payload expires it's refreshed.
+    pub lead: u64,
+    /// How many requests to a host's rate limit are kept for requests that
+    /// miss the cache. Payloads aren't warmed while fewer are left.
+    pub reserve: u64,
+}
+
+impl Default for WarmingConfig {
+    fn default() -> WarmingConfig {
+        WarmingConfig {
+            enabled: true,
+            interval: 30,
+            popular: 100,
+            lead: 60,
+            reserve: 500,
+        }
+    }
+}
+
+struct Popularity {
+    service: String,
+    hits: u64,
+    /// How the payload was last fetched, to warm it the same way.
+    fetcher: Fetcher,
+}
+
+/// Keeps the most requested payloads warm, refreshing them shortly before
+/// they expire so that requests for popular scieldas never miss the cache.
+///
+/// Requests are counted per URL, and the counts halved each time the popular
+/// payloads are checked, so that popularity reflects recent requests.
+///
+/// As a fairing, it starts checking the popular payloads periodically once
+/// Rocket has lifted off. The warmer is cheap to clone, with clones sharing
+/// the same counts, so that it can be both attached and managed.
+#[derive(Clone)]
+pub struct Warmer {
+    config: Arc<WarmingConfig>,
+    hits: Arc<Mutex<HashMap<String, Popularity>>>,
+}
+
+impl Warmer {
+    pub fn new(config: WarmingConfig) -> Warmer {
+        Warmer {
+            config: Arc::new(config),
+            hits: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// How many requests to a host's rate limit are kept for requests that
+    /// miss the cache.
+    pub fn reserve(&self) -> u64 {
+        self.config.reserve
+    }
+
+    /// Counts a request for the payload at a URL, fetched with `fetcher`.
+    pub fn record(&self, service: &str, url: &str, fetcher: &Fetcher) {
+        if !self.config.enabled {
+            return;
+        }
+        let mut hits = self.hits.lock().unwrap();
+        let popularity = hits.entry(url.to_string()).or_insert_with(|| Popularity {
+            service: service.to_string(),
+            hits: 0,
+            fetcher: fetcher.clone(),
+        });
+        popularity.hits += 1;
+        popularity.fetcher = fetcher.clone();
+    }
+
+    /// How the payload at a URL was last fetched, if it's been requested
+    /// recently.
+    pub fn fetcher(&self, url: &str) -> Option<Fetcher> {
+        let hits = self.hits.lock().unwrap();
+        hits.get(url).map(|p| p.fetcher.clone())
+    }
+
+    /// The most requested URLs, with their services and how they're fetched,
+    /// most requested first. The counts then decay, and URLs no longer
+    /// requested are forgotten.
+    fn popular(&self) -> Vec<(String, String, Fetcher)> {
+        let mut hits = self.hits.lock().unwrap();
+
+        let mut popular: Vec<_> = hits
+            .iter()
+            .map(|(url, p)| (p.hits, p.service.clone(), url.clone(), p.fetcher.clone()))
+            .collect();
+        popular.sort_unstable_by(|a, b| (b.0, &b.1, &b.2).cmp(&(a.0, &a.1, &a.2)));
+        popular.truncate(self.config.popular);
+
+        hits.retain(|_, p| {
+            p.hits /= 2;
+            p.hits > 0
+        });
+
+        popular
+            .into_iter()
+            .map(|(_, service, url, fetcher)| (service, url, fetcher))
+            .collect()
+    }
+
+    /// Refreshes the popular payloads due to expire, the way they were last
+    /// fetched, unless the rate limit that spends from is running low,
+    /// returning how many were refreshed.
+    pub async fn warm(
+        &self,
+        client: &UpstreamClient,
+        cache: &PayloadCache,
+        in_flight: &InFlight,
+    ) -> usize {
+        let lead = Duration::from_secs(self.config.lead);
+        let mut warmed = 0;
+
+        for (service, url, fetcher) in self.popular() {
+            let due = match cache.expires_in(&service, &url) {
+                Some(expires_in) => expires_in <= lead,
+                None => true,
+            };
+            let headroom = fetcher.headroom(client).unwrap_or(u64::MAX);
+            if !due || headroom < self.config.reserve {
+                continue;
+            }
+
+            // Failures are cached like any other fetch, so needn't be handled.
+            let _ = fetcher.start(client, cache, in_flight).await;
+            warmed += 1;
+        }
+
+        warmed
+    }
+}
+
+#[rocket::async_trait]
+impl Fairing for Warmer {
+    fn info(&self) -> Info {
+        Info {
+            name: "Cache Warming",
+            kind: Kind::Liftoff,
+        }
+    }
+
+    async fn on_liftoff(&self, rocket: &Rocket<rocket::Orbit>) {
+        if !self.config.enabled {
+            return;
+        }
+
+        let (client, cache, in_flight) = match (
+            rocket.state::<UpstreamClient>(),
+            rocket.state::<PayloadCache>(),
+            rocket.state::<InFlight>(),
+        ) {
+            (Some(client), Some(cache), Some(in_flight)) => {
+                (client.clone(), cache.clone(), in_flight.clone())
+            }
+            _ => return,
+        };
+
+        let warmer = self.clone();
+        let mut shutdown = rocket.shutdown();
+        let mut interval = time::interval(Duration::from_secs(self.config.interval.max(1)));
+        rocket::tokio::spawn(async move {
+            loop {
+                rocket::tokio::select! {
+                    _ = interval.tick() => {
+                        let warmed = warmer.warm(&client, &cache, &in_flight).await;
+                        log::debug!("Warmed {} popular payloads", warmed);
+                    }
+                    _ = &mut shutdown => break,
+                }
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cache::CacheConfig;
+    use crate::client::ClientConfig;
+    use crate::tokens::TokenPool;
+    use serde_json::json;
+    use std::time::{SystemTime, UNIX_EPOCH};
+    use wiremock::matchers::{method, path};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    /// Counts a request for a payload fetched from its URL.
+    fn record(warmer: &Warmer, service: &str, url: &str) {
+        warmer.record(service, url, &Fetcher::get(service, url));
+    }
+
+    #[test]
+    fn test_popular() {
+        let warmer = Warmer::new(WarmingConfig {
+            popular: 2,
+            ..WarmingConfig::default()
+        });
+        for (url, hits) in [("a", 1), ("b", 4), ("c", 2)] {
+            for _ in 0..hits {
+                record(&warmer, "github_stars", url);
+            }
+        }
+
+        let urls = |popular: Vec<(String, String, Fetcher)>| -> Vec<String> {
+            popular.into_iter().map(|(_, url, _)| url).collect()
+        };
+        assert_eq!(urls(warmer.popular()), vec!["b", "c"]);
+        // The counts have halved, to 2 and 1, and "a" has been forgotten.
+        assert_eq!(urls(warmer.popular()), vec!["b", "c"]);
+        assert_eq!(urls(warmer.popular()), vec!["b"]);
+        assert!(warmer.popular().is_empty());
+    }
+
+    #[rocket::async_test]
+    async fn test_warm() {
+        warm_with(vec![]).await;
+        warm_with(vec!["token".to_string()]).await;
+    }
+
+    async fn warm_with(tokens: Vec<String>) {
+        let server = MockServer::start().await;
+        let reset = SystemTime::now() + Duration::from_secs(3600);
+        let reset = reset.duration_since(UNIX_EPOCH).unwrap().as_secs();
+        for (route, remaining) in [("/plenty", "1000"), ("/scarce", "10")] {
+            Mock::given(method("GET"))
+                .and(path(route))
+                .respond_with(
+                    ResponseTemplate::new(200)
+                        .set_body_json(json!(1))
+                        .insert_header("X-RateLimit-Remaining", remaining)
+                        .insert_header("X-RateLimit-Reset", reset.to_string().as_str()),
+                )
+                .mount(&server)
+                .await;
+        }
+
+        let pool = TokenPool::new("127.0.0.1", tokens);
+        let client = UpstreamClient::new(ClientConfig::default(), vec![pool]).unwrap();
+        let cache = PayloadCache::new(CacheConfig::default());
+        let in_flight = InFlight::default();
+        let warmer = Warmer::new(WarmingConfig {
+            lead: 60,
+            ..WarmingConfig::default()
+        });
+
+        let plenty = format!("{}/plenty", server.uri());
+        record(&warmer, "github_stars", &plenty);
+        assert_eq!(warmer.warm(&client, &cache, &in_flight).await, 1);
+
+        // Freshly fetched, the payload isn't due to be refreshed.
+        record(&warmer, "github_stars", &plenty);
+        assert_eq!(warmer.warm(&client, &cache, &in_flight).await, 0);
+
+        let warmer = Warmer::new(WarmingConfig {
+            lead: 300,
+            ..WarmingConfig::default()
+        });
+        record(&warmer, "github_stars", &plenty);
+        assert_eq!(warmer.warm(&client, &cache, &in_flight).await, 1);
+
+        // Once the host reports its rate limit is running low, nothing more
+        // is warmed from it.
+        let scarce = format!("{}/scarce", server.uri());
+        record(&warmer, "github_stars", &scarce);
+        assert_eq!(warmer.warm(&client, &cache, &in_flight).await, 1);
+        record(&warmer, "github_stars", &scarce);
+        record(&warmer, "github_stars", &plenty);
+        assert_eq!(warmer.warm(&client, &cache, &in_flight).await, 0);
+
+        assert_eq!(server.received_requests().await.unwrap().len(), 3);
+    }
+}